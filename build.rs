@@ -0,0 +1,18 @@
+use std::env;
+
+// Controls newer than the minimum libvpx version required by vpx-sys
+const VERSIONS: &[(&str, (u32, u32))] = &[("vpx_1_7", (1, 7)), ("vpx_1_8", (1, 8))];
+
+fn main() {
+    let version = env::var("DEP_VPX_VERSION").unwrap_or_default();
+    let mut parts = version.split('.').map(|v| v.parse().unwrap_or(0));
+    let version = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+
+    for &(cfg, min) in VERSIONS {
+        println!("cargo:rustc-check-cfg=cfg({})", cfg);
+        if version >= min {
+            println!("cargo:rustc-cfg={}", cfg);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
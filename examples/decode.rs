@@ -1 +1,366 @@
-fn main() {}
+//! Decode a VP8 or VP9 IVF or WebM file to Y4M or raw planes
+//!
+//! `cargo run --example decode -- [options] <input.ivf|input.webm>`
+//!
+//! WebM input requires the `webm` feature.
+//!
+//! `--md5` prints the digest of the output as `vpxdec --md5` does,
+//! `--md5-frames` prints one digest per picture in the format of the
+//! libvpx test vectors `.md5` files.
+
+use libvpx::common::VPXCodecId;
+use libvpx::decoder::{DecodedImage, VP9Decoder, VP9DecoderConfig};
+use libvpx::ivf;
+#[cfg(feature = "webm")]
+use libvpx::webm;
+use libvpx::y4m;
+use vpx_sys::*;
+
+#[cfg(feature = "webm")]
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: decode [options] <input.ivf|input.webm>
+
+Options:
+  -o <file>             Output file
+  --rawvideo            Write raw planes instead of Y4M
+  --limit <n>           Decode at most n frames
+  --threads <n>         Number of threads
+  --md5                 Print the MD5 of the output
+  --md5-frames          Print the MD5 of every picture";
+
+#[derive(Default)]
+struct Options {
+    input: String,
+    output: Option<String>,
+    raw: bool,
+    limit: Option<u64>,
+    threads: u32,
+    md5: bool,
+    md5_frames: bool,
+}
+
+fn parse<T: std::str::FromStr>(name: &str, val: String) -> Result<T, String> {
+    val.parse()
+        .map_err(|_| format!("Invalid value for {}: {}", name, val))
+}
+
+impl Options {
+    fn from_args() -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut files = Vec::new();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", name))
+            };
+
+            match arg.as_str() {
+                "--help" => return Err(String::new()),
+                "-o" => opts.output = Some(value("-o")?),
+                "--rawvideo" | "--i420" => opts.raw = true,
+                "--limit" => opts.limit = Some(parse("--limit", value("--limit")?)?),
+                "--threads" => opts.threads = parse("--threads", value("--threads")?)?,
+                "--md5" => opts.md5 = true,
+                "--md5-frames" => opts.md5_frames = true,
+                _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+                _ => files.push(arg),
+            }
+        }
+
+        if files.len() != 1 {
+            return Err("Expected an input file".into());
+        }
+        opts.input = files.pop().unwrap();
+
+        Ok(opts)
+    }
+}
+
+/// Destination of the decoded pictures, a file and/or a digest
+struct Sink {
+    file: Option<BufWriter<File>>,
+    md5: Option<md5::Context>,
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(data)?;
+        }
+        if let Some(md5) = self.md5.as_mut() {
+            md5.consume(data);
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Y4M or raw output, set up from the first picture
+struct Output {
+    writer: Option<y4m::Writer<Sink>>,
+    sink: Option<Sink>,
+    raw: bool,
+}
+
+impl Output {
+    fn write_image(&mut self, img: &DecodedImage<'_>, framerate: (u32, u32)) -> Result<(), String> {
+        let header = y4m::Header::from_image(img, framerate);
+
+        // Raw planes may change size, a Y4M stream has a single header.
+        if self.raw && matches!(self.writer, Some(ref w) if *w.header() != header) {
+            let w = self.writer.take().unwrap();
+            self.sink = Some(w.into_inner().map_err(|e| e.to_string())?);
+        }
+
+        let w = match self.writer {
+            Some(ref mut w) => w,
+            None => {
+                let sink = self.sink.take().unwrap();
+                let w = match self.raw {
+                    true => y4m::Writer::raw(sink, &header),
+                    false => y4m::Writer::new(sink, &header),
+                };
+                self.writer.get_or_insert(w.map_err(|e| e.to_string())?)
+            }
+        };
+
+        w.write_image(img).map_err(|e| e.to_string())
+    }
+
+    fn finish(mut self) -> Result<Sink, String> {
+        match self.writer.take() {
+            Some(w) => w.into_inner().map_err(|e| e.to_string()),
+            None => Ok(self.sink.take().unwrap()),
+        }
+    }
+}
+
+fn frame_md5(img: &DecodedImage<'_>) -> md5::Digest {
+    let mut md5 = md5::Context::new();
+
+    for idx in 0..img.plane_count() {
+        for row in img.rows(idx) {
+            md5.consume(row);
+        }
+    }
+
+    md5.compute()
+}
+
+/// Container of the compressed stream, detected from its signature
+enum Demuxer {
+    Ivf(ivf::Reader<BufReader<File>>),
+    /// The packets read ahead to guess the frame rate are returned first
+    #[cfg(feature = "webm")]
+    Webm(webm::Reader<BufReader<File>>, VecDeque<Vec<u8>>),
+}
+
+impl Demuxer {
+    fn open(name: &str) -> Result<Demuxer, String> {
+        let file = File::open(name).map_err(|e| format!("{}: {}", name, e))?;
+        let mut file = BufReader::new(file);
+        let webm = file
+            .fill_buf()
+            .map_err(|e| e.to_string())?
+            .starts_with(&[0x1a, 0x45, 0xdf, 0xa3]);
+
+        match webm {
+            #[cfg(feature = "webm")]
+            true => webm::Reader::new(file).map(|r| Demuxer::Webm(r, VecDeque::new())),
+            #[cfg(not(feature = "webm"))]
+            true => return Err("WebM input requires the webm feature".into()),
+            false => ivf::Reader::new(file).map(Demuxer::Ivf),
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    fn codec(&self) -> VPXCodecId {
+        match self {
+            Demuxer::Ivf(r) => r.header().codec,
+            #[cfg(feature = "webm")]
+            Demuxer::Webm(r, _) => r.track().codec,
+        }
+    }
+
+    /// Frame rate of the output, as numerator and denominator
+    ///
+    /// IVF stores it as the inverse of the timebase.
+    fn framerate(&mut self) -> Result<(u32, u32), String> {
+        match self {
+            Demuxer::Ivf(r) => {
+                let (num, den) = r.header().timebase;
+                Ok((den, num))
+            }
+            #[cfg(feature = "webm")]
+            Demuxer::Webm(r, pending) => guess_framerate(r, pending),
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            Demuxer::Ivf(r) => r.read_packet().map(|pkt| pkt.map(|pkt| pkt.data)),
+            #[cfg(feature = "webm")]
+            Demuxer::Webm(r, pending) => match pending.pop_front() {
+                Some(data) => Ok(Some(data)),
+                None => r.read_packet().map(|pkt| pkt.map(|pkt| pkt.data)),
+            },
+        }
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// Guess the frame rate of a WebM stream, which only has timestamps
+///
+/// As `vpxdec` does, it counts the packets of the first second, those
+/// are kept in `pending`.
+#[cfg(feature = "webm")]
+fn guess_framerate(
+    r: &mut webm::Reader<BufReader<File>>,
+    pending: &mut VecDeque<Vec<u8>>,
+) -> Result<(u32, u32), String> {
+    use std::convert::TryFrom;
+
+    let (num, den) = r.timebase();
+    let mut ns = 0;
+
+    while ns < 1_000_000_000 && pending.len() < 50 {
+        match r.read_packet().map_err(|e| e.to_string())? {
+            Some(pkt) => {
+                ns = pkt.pts.max(0) as u128 * u128::from(num) * 1_000_000_000 / u128::from(den);
+                pending.push_back(pkt.data);
+            }
+            None => break,
+        }
+    }
+
+    let num = pending.len().saturating_sub(1) as u32 * 1_000_000;
+    match u32::try_from(ns / 1000) {
+        Ok(den) if num != 0 && den != 0 => Ok((num, den)),
+        _ => Ok((30, 1)),
+    }
+}
+
+fn vpx_error(what: &str, err: vpx_codec_err_t) -> String {
+    format!("{} failed: {:?}", what, err)
+}
+
+fn run(opts: &Options) -> Result<(), String> {
+    let mut packets = Demuxer::open(&opts.input)?;
+    let codec = packets.codec();
+
+    let cfg = VP9DecoderConfig {
+        codec,
+        threads: opts.threads,
+        ..VP9DecoderConfig::new()
+    };
+    let mut dec: VP9Decoder<()> = cfg
+        .get_decoder()
+        .map_err(|e| vpx_error("Decoder setup", e))?;
+
+    let out_file = match opts.output {
+        Some(ref name) => {
+            let file = File::create(name).map_err(|e| format!("{}: {}", name, e))?;
+            Some(BufWriter::new(file))
+        }
+        None => None,
+    };
+    let sink = Sink {
+        file: out_file,
+        md5: match opts.md5 {
+            true => Some(md5::Context::new()),
+            false => None,
+        },
+    };
+    let mut output = Output {
+        writer: None,
+        sink: Some(sink),
+        raw: opts.raw,
+    };
+    let framerate = packets.framerate()?;
+
+    let stem = Path::new(&opts.input)
+        .file_stem()
+        .map_or("-".into(), |s| s.to_string_lossy());
+    let limit = opts.limit.unwrap_or(u64::MAX);
+    let mut frames = 0;
+    let mut flushed = false;
+
+    while !flushed && frames < limit {
+        match packets.read_packet()? {
+            Some(data) => dec.decode(&data, None),
+            None => {
+                flushed = true;
+                dec.flush()
+            }
+        }
+        .map_err(|e| vpx_error("Decoding", e))?;
+
+        while let Some((img, _)) = dec.get_frame_ref() {
+            if frames == limit {
+                break;
+            }
+            frames += 1;
+
+            if opts.md5_frames {
+                println!(
+                    "{:x}  {}-{}x{}-{:04}.i420",
+                    frame_md5(&img),
+                    stem,
+                    img.width(),
+                    img.height(),
+                    frames
+                );
+            }
+            output.write_image(&img, framerate)?;
+        }
+    }
+
+    let sink = output.finish()?;
+    if let Some(md5) = sink.md5 {
+        println!(
+            "{:x}  {}",
+            md5.compute(),
+            opts.output.as_deref().unwrap_or("-")
+        );
+    }
+
+    let codec = match codec {
+        VPXCodecId::VP8 => "VP8",
+        VPXCodecId::VP9 => "VP9",
+    };
+    eprintln!("Decoded {} {} frames", frames, codec);
+
+    Ok(())
+}
+
+fn main() {
+    let opts = match Options::from_args() {
+        Ok(opts) => opts,
+        Err(msg) => {
+            if !msg.is_empty() {
+                eprintln!("{}\n", msg);
+            }
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(msg) = run(&opts) {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+}
@@ -1 +1,368 @@
-fn main() {}
+//! Encode a Y4M or raw I420 file to VP9 in IVF or WebM
+//!
+//! `cargo run --example encode -- [options] <input> <output.ivf>`
+//!
+//! The output is WebM if its name ends in `.webm`, that requires the
+//! `webm` feature.
+
+use libvpx::common::ImageFormat;
+use libvpx::encoder::{VP9EncoderConfig, VPXPacket};
+use libvpx::ivf;
+#[cfg(feature = "webm")]
+use libvpx::webm;
+use libvpx::y4m;
+use vpx_sys::*;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process;
+
+const USAGE: &str = "Usage: encode [options] <input.y4m|input.yuv> <output.ivf|output.webm>
+
+Options:
+  --width <w>           Width of a raw input
+  --height <h>          Height of a raw input
+  --fps <num/den>       Frame rate of a raw input [30/1]
+  --limit <n>           Encode at most n frames
+  --bitrate <kbps>      Target bitrate
+  --end-usage <mode>    Rate control: vbr, cbr, cq or q
+  --cq-level <q>        Quality for the cq and q modes
+  --cpu-used <n>        Speed setting
+  --threads <n>         Number of threads
+  --passes <1|2>        Number of passes [1]
+  --kf-max-dist <n>     Maximum distance between keyframes
+  --deadline <dl>       good, rt, best or a budget in microseconds [good]";
+
+#[derive(Default)]
+struct Options {
+    input: String,
+    output: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    fps: Option<(u32, u32)>,
+    limit: Option<u64>,
+    bitrate: Option<u32>,
+    end_usage: Option<vpx_rc_mode>,
+    cq_level: Option<u32>,
+    cpu_used: Option<i32>,
+    threads: Option<u32>,
+    passes: u32,
+    kf_max_dist: Option<u32>,
+    deadline: u64,
+}
+
+fn parse<T: std::str::FromStr>(name: &str, val: &str) -> Result<T, String> {
+    val.parse()
+        .map_err(|_| format!("Invalid value for {}: {}", name, val))
+}
+
+fn parse_ratio(name: &str, val: &str) -> Result<(u32, u32), String> {
+    let mut it = val.splitn(2, ['/', ':']);
+    let num = parse(name, it.next().unwrap_or(""))?;
+    let den = it.next().map_or(Ok(1), |d| parse(name, d))?;
+
+    match (num, den) {
+        (0, _) | (_, 0) => Err(format!("Invalid value for {}: {}", name, val)),
+        r => Ok(r),
+    }
+}
+
+impl Options {
+    fn from_args() -> Result<Options, String> {
+        let mut opts = Options {
+            passes: 1,
+            deadline: u64::from(VPX_DL_GOOD_QUALITY),
+            ..Default::default()
+        };
+        let mut files = Vec::new();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                files.push(arg);
+                continue;
+            }
+            if arg == "--help" {
+                return Err(String::new());
+            }
+
+            let name = arg.as_str();
+            let val = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", name))?;
+
+            match name {
+                "--width" => opts.width = Some(parse(name, &val)?),
+                "--height" => opts.height = Some(parse(name, &val)?),
+                "--fps" => opts.fps = Some(parse_ratio(name, &val)?),
+                "--limit" => opts.limit = Some(parse(name, &val)?),
+                "--bitrate" => opts.bitrate = Some(parse(name, &val)?),
+                "--end-usage" => {
+                    opts.end_usage = Some(match val.as_str() {
+                        "vbr" => vpx_rc_mode::VPX_VBR,
+                        "cbr" => vpx_rc_mode::VPX_CBR,
+                        "cq" => vpx_rc_mode::VPX_CQ,
+                        "q" => vpx_rc_mode::VPX_Q,
+                        _ => return Err(format!("Invalid value for {}: {}", name, val)),
+                    })
+                }
+                "--cq-level" => opts.cq_level = Some(parse(name, &val)?),
+                "--cpu-used" => opts.cpu_used = Some(parse(name, &val)?),
+                "--threads" => opts.threads = Some(parse(name, &val)?),
+                "--passes" => {
+                    opts.passes = match val.as_str() {
+                        "1" => 1,
+                        "2" => 2,
+                        _ => return Err(format!("Invalid value for {}: {}", name, val)),
+                    }
+                }
+                "--kf-max-dist" => opts.kf_max_dist = Some(parse(name, &val)?),
+                "--deadline" => {
+                    opts.deadline = match val.as_str() {
+                        "good" => u64::from(VPX_DL_GOOD_QUALITY),
+                        "rt" => u64::from(VPX_DL_REALTIME),
+                        "best" => u64::from(VPX_DL_BEST_QUALITY),
+                        _ => parse(name, &val)?,
+                    }
+                }
+                _ => return Err(format!("Unknown option {}", name)),
+            }
+        }
+
+        if files.len() != 2 {
+            return Err("Expected an input and an output file".into());
+        }
+
+        opts.output = files.pop().unwrap();
+        opts.input = files.pop().unwrap();
+
+        Ok(opts)
+    }
+}
+
+type Input = y4m::Reader<BufReader<File>>;
+
+/// Open the uncompressed input, either Y4M or raw I420 planes
+fn open_input(opts: &Options) -> Result<Input, String> {
+    let file = File::open(&opts.input).map_err(|e| format!("{}: {}", opts.input, e))?;
+    let mut reader = BufReader::new(file);
+    let is_y4m = reader
+        .fill_buf()
+        .map_err(|e| e.to_string())?
+        .starts_with(b"YUV4MPEG2 ");
+
+    if is_y4m {
+        return y4m::Reader::new(reader).map_err(|e| format!("{}: {}", opts.input, e));
+    }
+
+    let (width, height) = match (opts.width, opts.height) {
+        (Some(w), Some(h)) => (w, h),
+        _ => return Err("The size of a raw input must be set with --width and --height".into()),
+    };
+    let format = ImageFormat::new(vpx_img_fmt_t::VPX_IMG_FMT_I420, 8).unwrap();
+    let header = y4m::Header {
+        framerate: opts.fps.unwrap_or((30, 1)),
+        ..y4m::Header::new(width, height, format)
+    };
+
+    y4m::Reader::raw(reader, &header).map_err(|e| e.to_string())
+}
+
+fn vpx_error(what: &str, err: vpx_codec_err_t) -> String {
+    format!("{} failed: {:?}", what, err)
+}
+
+fn configure(opts: &Options, header: &y4m::Header) -> Result<VP9EncoderConfig, String> {
+    let mut c = VP9EncoderConfig::new().map_err(|e| vpx_error("Configuration", e))?;
+    let format = header.format;
+    let (num, den) = header.timebase();
+
+    c.cfg.g_w = header.width;
+    c.cfg.g_h = header.height;
+    c.cfg.g_timebase.num = num as i32;
+    c.cfg.g_timebase.den = den as i32;
+    c.cfg.g_profile = match (format.is_high_bitdepth(), format.chroma_shift()) {
+        (false, (1, 1)) => 0,
+        (false, _) => 1,
+        (true, (1, 1)) => 2,
+        (true, _) => 3,
+    };
+
+    if format.is_high_bitdepth() {
+        c.cfg.g_bit_depth = match format.bit_depth() {
+            10 => vpx_bit_depth::VPX_BITS_10,
+            _ => vpx_bit_depth::VPX_BITS_12,
+        };
+        c.cfg.g_input_bit_depth = format.bit_depth();
+    }
+
+    if let Some(bitrate) = opts.bitrate {
+        c.cfg.rc_target_bitrate = bitrate;
+    }
+    if let Some(mode) = opts.end_usage {
+        c.cfg.rc_end_usage = mode;
+    }
+    if let Some(threads) = opts.threads {
+        c.cfg.g_threads = threads;
+    }
+    if let Some(dist) = opts.kf_max_dist {
+        c.cfg.kf_max_dist = dist;
+    }
+
+    Ok(c)
+}
+
+/// Container of the encoded stream
+enum Muxer {
+    Ivf(ivf::Writer<File>),
+    #[cfg(feature = "webm")]
+    Webm(webm::Writer<File>),
+}
+
+impl Muxer {
+    #[cfg_attr(not(feature = "webm"), allow(unused_variables))]
+    fn create(
+        opts: &Options,
+        cfg: &VP9EncoderConfig,
+        format: ImageFormat,
+    ) -> Result<Muxer, String> {
+        let webm = opts.output.ends_with(".webm");
+        if webm && cfg!(not(feature = "webm")) {
+            return Err("WebM output requires the webm feature".into());
+        }
+        let file = File::create(&opts.output).map_err(|e| format!("{}: {}", opts.output, e))?;
+
+        match webm {
+            #[cfg(feature = "webm")]
+            true => {
+                let track = webm::Track::from_vp9_config(cfg, Some(format));
+                let timebase = (cfg.cfg.g_timebase.num as u32, cfg.cfg.g_timebase.den as u32);
+                webm::Writer::new(file, &track, timebase).map(Muxer::Webm)
+            }
+            #[cfg(not(feature = "webm"))]
+            true => unreachable!(),
+            false => ivf::Header::from_vp9_config(cfg)
+                .and_then(|header| ivf::Writer::new(file, &header))
+                .map(Muxer::Ivf),
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    fn write_packet(&mut self, pkt: &VPXPacket) -> Result<(), String> {
+        match self {
+            Muxer::Ivf(w) => w.write_packet(pkt),
+            #[cfg(feature = "webm")]
+            Muxer::Webm(w) => w.write_packet(pkt),
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            Muxer::Ivf(w) => w.finish().map(drop),
+            #[cfg(feature = "webm")]
+            Muxer::Webm(w) => w.finish().map(drop),
+        }
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// Run one encoding pass, return the first pass statistics
+fn encode(
+    opts: &Options,
+    cfg: &mut VP9EncoderConfig,
+    mut input: Input,
+    mut output: Option<&mut Muxer>,
+) -> Result<Vec<u8>, String> {
+    let mut enc = cfg
+        .get_encoder()
+        .map_err(|e| vpx_error("Encoder setup", e))?;
+
+    enc.set_deadline(opts.deadline);
+    if let Some(cpu_used) = opts.cpu_used {
+        enc.control(vp8e_enc_control_id::VP8E_SET_CPUUSED, cpu_used)
+            .map_err(|e| vpx_error("Setting cpu-used", e))?;
+    }
+    if let Some(level) = opts.cq_level {
+        enc.control(vp8e_enc_control_id::VP8E_SET_CQ_LEVEL, level as i32)
+            .map_err(|e| vpx_error("Setting cq-level", e))?;
+    }
+
+    let mut stats = Vec::new();
+    let mut pts = 0;
+
+    loop {
+        // The frames carry their color range, the encoder signals it.
+        let frame = match opts.limit {
+            Some(limit) if pts >= limit => None,
+            _ => input
+                .read_frame()
+                .map_err(|e| format!("{}: {}", opts.input, e))?,
+        };
+        let more = frame.is_some();
+
+        match frame {
+            Some(ref frame) => enc.encode(frame),
+            None => enc.flush(),
+        }
+        .map_err(|e| vpx_error("Encoding", e))?;
+
+        for pkt in enc.packets() {
+            match pkt {
+                VPXPacket::Stats(data) => stats.extend_from_slice(&data),
+                VPXPacket::Packet(_) => {
+                    if let Some(w) = output.as_mut() {
+                        w.write_packet(&pkt)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !more {
+            break;
+        }
+        pts += 1;
+    }
+
+    eprintln!("Encoded {} frames", pts);
+
+    Ok(stats)
+}
+
+fn run(opts: &Options) -> Result<(), String> {
+    let input = open_input(opts)?;
+    let header = *input.header();
+    let mut cfg = configure(opts, &header)?;
+
+    if opts.passes == 2 {
+        cfg.cfg.g_pass = vpx_enc_pass::VPX_RC_FIRST_PASS;
+        let stats = encode(opts, &mut cfg, open_input(opts)?, None)?;
+
+        cfg.cfg.g_pass = vpx_enc_pass::VPX_RC_LAST_PASS;
+        cfg.twopass_stats = Some(stats.into());
+    }
+
+    let mut muxer = Muxer::create(opts, &cfg, header.format)?;
+    encode(opts, &mut cfg, input, Some(&mut muxer))?;
+
+    muxer.finish()
+}
+
+fn main() {
+    let opts = match Options::from_args() {
+        Ok(opts) => opts,
+        Err(msg) => {
+            if !msg.is_empty() {
+                eprintln!("{}\n", msg);
+            }
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(msg) = run(&opts) {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+}
@@ -1,6 +1,32 @@
 use crate::ffi::*;
 use std::ffi::CStr;
 
+#[cfg(feature = "av-data")]
+use crate::vpcc::{color_space_from_iso, color_space_to_iso, formaton_full_range};
+#[cfg(feature = "av-data")]
+use av_data::pixel::{
+    Chromaton, ColorModel, ColorPrimaries, Formaton, FromPrimitive, MatrixCoefficients,
+    TransferCharacteristic, TrichromaticEncodingSystem, YUVRange, YUVSystem,
+};
+
+/// The codecs provided by libvpx
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VPXCodecId {
+    VP8,
+    VP9,
+}
+
+impl VPXCodecId {
+    pub(crate) fn decoder_iface(self) -> *mut vpx_codec_iface_t {
+        unsafe {
+            match self {
+                VPXCodecId::VP8 => vpx_codec_vp8_dx(),
+                VPXCodecId::VP9 => vpx_codec_vp9_dx(),
+            }
+        }
+    }
+}
+
 pub trait VPXCodec {
     fn get_context(&mut self) -> &mut vpx_codec_ctx;
 
@@ -13,3 +39,352 @@ pub trait VPXCodec {
         }
     }
 }
+
+/// Build the `Formaton` describing a planar YUV layout
+///
+/// The shifts match the `x_chroma_shift` and `y_chroma_shift` of a `vpx_image`.
+#[cfg(feature = "av-data")]
+pub(crate) fn yuv_formaton(x_chroma_shift: u32, y_chroma_shift: u32, depth: u32) -> Formaton {
+    let (xs, ys, d) = (x_chroma_shift as u8, y_chroma_shift as u8, depth as u8);
+
+    Formaton::new(
+        ColorModel::Trichromatic(TrichromaticEncodingSystem::YUV(YUVSystem::YCbCr(
+            YUVRange::Limited,
+        ))),
+        &[
+            Chromaton::yuvhb(0, 0, d, 0),
+            Chromaton::yuvhb(xs, ys, d, 1),
+            Chromaton::yuvhb(xs, ys, d, 2),
+        ],
+        0,
+        false,
+        false,
+        false,
+    )
+}
+
+/// Color space and range described by a `Formaton`
+///
+/// Either is `None` if the format leaves it unspecified.
+#[cfg(feature = "av-data")]
+pub(crate) fn formaton_color(
+    fmt: &Formaton,
+) -> (Option<vpx_color_space_t>, Option<vpx_color_range_t>) {
+    let cs = color_space_from_iso(fmt.matrix as u8);
+    let range = formaton_full_range(fmt).map(|full| {
+        if full {
+            vpx_color_range_t::VPX_CR_FULL_RANGE
+        } else {
+            vpx_color_range_t::VPX_CR_STUDIO_RANGE
+        }
+    });
+
+    (cs, range)
+}
+
+/// Layout of an uncompressed planar YUV image
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImageFormat {
+    fmt: vpx_img_fmt_t,
+    bit_depth: u32,
+}
+
+impl ImageFormat {
+    /// Pair a libvpx pixel format with the bits per sample
+    ///
+    /// Returns `None` if the format is not planar YUV or if the bit depth
+    /// does not fit the sample size.
+    pub fn new(fmt: vpx_img_fmt_t, bit_depth: u32) -> Option<ImageFormat> {
+        use self::vpx_img_fmt_t::*;
+
+        let high = match fmt {
+            VPX_IMG_FMT_I420 | VPX_IMG_FMT_I422 | VPX_IMG_FMT_I444 | VPX_IMG_FMT_I440 => false,
+            VPX_IMG_FMT_I42016 | VPX_IMG_FMT_I42216 | VPX_IMG_FMT_I44416 | VPX_IMG_FMT_I44016 => {
+                true
+            }
+            _ => return None,
+        };
+
+        match (bit_depth, high) {
+            (8, false) | (8, true) | (10, true) | (12, true) => {
+                Some(ImageFormat { fmt, bit_depth })
+            }
+            _ => None,
+        }
+    }
+
+    /// Derive the layout from a `vpx_image`
+    pub fn from_image(img: &vpx_image) -> Option<ImageFormat> {
+        ImageFormat::new(img.fmt, img.bit_depth)
+    }
+
+    /// Derive the layout from a `Formaton`
+    ///
+    /// Only planar YUV formats with 8, 10 or 12 bits per sample are
+    /// supported, samples deeper than 8 bits are stored in 16 bits.
+    #[cfg(feature = "av-data")]
+    pub fn from_formaton(fmt: &Formaton) -> Option<ImageFormat> {
+        use self::vpx_img_fmt_t::*;
+
+        match fmt.model {
+            ColorModel::Trichromatic(TrichromaticEncodingSystem::YUV(_)) => {}
+            _ => return None,
+        }
+
+        if fmt.get_num_comp() != 3 || fmt.has_alpha() || fmt.is_paletted() {
+            return None;
+        }
+
+        let luma = fmt.get_chromaton(0)?;
+        let chroma = fmt.get_chromaton(1)?;
+        let other = fmt.get_chromaton(2)?;
+        // The depth is taken from luma only, the chroma depth of the
+        // predefined high bit depth formats is not reliable.
+        if luma.is_packed()
+            || (luma.h_ss, luma.v_ss) != (0, 0)
+            || (chroma.h_ss, chroma.v_ss) != (other.h_ss, other.v_ss)
+        {
+            return None;
+        }
+
+        let high = luma.depth > 8;
+        let fmt = match (chroma.h_ss, chroma.v_ss, high) {
+            (1, 1, false) => VPX_IMG_FMT_I420,
+            (1, 0, false) => VPX_IMG_FMT_I422,
+            (0, 0, false) => VPX_IMG_FMT_I444,
+            (0, 1, false) => VPX_IMG_FMT_I440,
+            (1, 1, true) => VPX_IMG_FMT_I42016,
+            (1, 0, true) => VPX_IMG_FMT_I42216,
+            (0, 0, true) => VPX_IMG_FMT_I44416,
+            (0, 1, true) => VPX_IMG_FMT_I44016,
+            _ => return None,
+        };
+
+        ImageFormat::new(fmt, luma.depth as u32)
+    }
+
+    /// Describe the layout as a `Formaton`
+    #[cfg(feature = "av-data")]
+    pub fn formaton(&self) -> Formaton {
+        let (xs, ys) = self.chroma_shift();
+
+        yuv_formaton(xs, ys, self.bit_depth)
+    }
+
+    /// Describe the layout and the color information as a `Formaton`
+    ///
+    /// The primaries, transfer characteristics and matrix coefficients are
+    /// the ones implied by the libvpx color space.
+    #[cfg(feature = "av-data")]
+    pub fn color_formaton(&self, cs: vpx_color_space_t, range: vpx_color_range_t) -> Formaton {
+        let mut fmt = self.formaton();
+
+        if range == vpx_color_range_t::VPX_CR_FULL_RANGE {
+            fmt.model = ColorModel::Trichromatic(TrichromaticEncodingSystem::YUV(
+                YUVSystem::YCbCr(YUVRange::Full),
+            ));
+        }
+
+        let (primaries, xfer, matrix) = color_space_to_iso(cs, self.bit_depth as u8);
+        if let (Some(primaries), Some(xfer), Some(matrix)) = (
+            ColorPrimaries::from_u8(primaries),
+            TransferCharacteristic::from_u8(xfer),
+            MatrixCoefficients::from_u8(matrix),
+        ) {
+            fmt.primaries = primaries;
+            fmt.xfer = xfer;
+            fmt.matrix = matrix;
+        }
+
+        fmt
+    }
+
+    /// libvpx pixel format
+    pub fn fmt(&self) -> vpx_img_fmt_t {
+        self.fmt
+    }
+
+    /// Bits per sample
+    pub fn bit_depth(&self) -> u32 {
+        self.bit_depth
+    }
+
+    /// Horizontal and vertical chroma subsampling, as shifts
+    pub fn chroma_shift(&self) -> (u32, u32) {
+        use self::vpx_img_fmt_t::*;
+
+        match self.fmt {
+            VPX_IMG_FMT_I420 | VPX_IMG_FMT_I42016 => (1, 1),
+            VPX_IMG_FMT_I422 | VPX_IMG_FMT_I42216 => (1, 0),
+            VPX_IMG_FMT_I440 | VPX_IMG_FMT_I44016 => (0, 1),
+            _ => (0, 0),
+        }
+    }
+
+    /// Tell whether the samples are stored in 16 bits
+    pub fn is_high_bitdepth(&self) -> bool {
+        self.fmt as u32 & VPX_IMG_FMT_HIGHBITDEPTH != 0
+    }
+
+    /// Bytes used by each sample
+    pub fn bytes_per_sample(&self) -> usize {
+        if self.is_high_bitdepth() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Width and height in samples of the `idx`-th plane of a `w`x`h` image
+    pub fn plane_size(&self, idx: usize, w: u32, h: u32) -> (usize, usize) {
+        let (xs, ys) = match idx {
+            0 => (0, 0),
+            _ => self.chroma_shift(),
+        };
+
+        // Round up without overflowing near u32::MAX.
+        (
+            ((w >> xs) + (w & ((1 << xs) - 1)).min(1)) as usize,
+            ((h >> ys) + (h & ((1 << ys) - 1)).min(1)) as usize,
+        )
+    }
+
+    /// Check that the planes hold a whole `w`x`h` image
+    ///
+    /// `planes` and `strides` are in bytes.
+    pub(crate) fn check_planes(
+        &self,
+        w: u32,
+        h: u32,
+        planes: &[&[u8]],
+        strides: &[usize],
+    ) -> Result<(), vpx_codec_err_t> {
+        if planes.len() < 3 || strides.len() < 3 {
+            return Err(vpx_codec_err_t::VPX_CODEC_INVALID_PARAM);
+        }
+
+        for (idx, (plane, &stride)) in planes.iter().zip(strides).take(3).enumerate() {
+            let (pw, ph) = self.plane_size(idx, w, h);
+            let row = pw * self.bytes_per_sample();
+            let size = match ph {
+                0 => Some(0),
+                _ => stride.checked_mul(ph - 1).and_then(|s| s.checked_add(row)),
+            };
+
+            if stride < row
+                || stride > i32::MAX as usize
+                || !matches!(size, Some(s) if plane.len() >= s)
+            {
+                return Err(vpx_codec_err_t::VPX_CODEC_INVALID_PARAM);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describe a `w`x`h` image, without planes
+    pub(crate) fn image(&self, w: u32, h: u32) -> vpx_image {
+        // This is sound because `vpx_image` is a repr(C) struct containing fields that can be
+        // zeroed without causing UB
+        let mut img: vpx_image = unsafe { std::mem::zeroed() };
+        let (xs, ys) = self.chroma_shift();
+        let bps = 8 + 16 / (1 << (xs + ys));
+
+        img.fmt = self.fmt;
+        img.bit_depth = self.bit_depth;
+        img.bps = (bps * self.bytes_per_sample()) as i32;
+        img.x_chroma_shift = xs;
+        img.y_chroma_shift = ys;
+        img.w = w;
+        img.h = h;
+        img.d_w = w;
+        img.d_h = h;
+
+        img
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::vpx_img_fmt_t::*;
+    use super::*;
+
+    #[test]
+    fn image_format() {
+        let f = ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap();
+        assert_eq!(f.chroma_shift(), (1, 1));
+        assert_eq!(f.plane_size(1, 175, 143), (88, 72));
+        assert_eq!(f.plane_size(1, u32::MAX, 1), (1 << 31, 1));
+
+        let f = ImageFormat::new(VPX_IMG_FMT_I44016, 12).unwrap();
+        assert_eq!(f.chroma_shift(), (0, 1));
+        assert_eq!(f.bytes_per_sample(), 2);
+
+        assert_eq!(ImageFormat::new(VPX_IMG_FMT_I420, 10), None);
+        assert_eq!(ImageFormat::new(VPX_IMG_FMT_YV12, 8), None);
+    }
+
+    #[cfg(feature = "av-data")]
+    #[test]
+    fn formaton() {
+        use av_data::pixel::formats::{RGB24, YUV420, YUV420_10, YUV444};
+
+        let f = ImageFormat::from_formaton(YUV420).unwrap();
+        assert_eq!((f.fmt(), f.bit_depth()), (VPX_IMG_FMT_I420, 8));
+
+        let f = ImageFormat::from_formaton(YUV420_10).unwrap();
+        assert_eq!((f.fmt(), f.bit_depth()), (VPX_IMG_FMT_I42016, 10));
+        assert_eq!(f.bytes_per_sample(), 2);
+
+        let f = ImageFormat::from_formaton(YUV444).unwrap();
+        assert_eq!(f.chroma_shift(), (0, 0));
+
+        let f = ImageFormat::from_formaton(&yuv_formaton(0, 1, 8)).unwrap();
+        assert_eq!(f.fmt(), VPX_IMG_FMT_I440);
+        assert_eq!(ImageFormat::from_formaton(&f.formaton()), Some(f));
+
+        assert_eq!(ImageFormat::from_formaton(RGB24), None);
+    }
+
+    #[cfg(feature = "av-data")]
+    #[test]
+    fn color_formaton() {
+        use self::vpx_color_range::*;
+        use self::vpx_color_space::*;
+        use av_data::pixel::formats::YUV420;
+
+        let f = ImageFormat::new(VPX_IMG_FMT_I42016, 10).unwrap();
+        let fmt = f.color_formaton(VPX_CS_BT_2020, VPX_CR_FULL_RANGE);
+
+        assert_eq!(fmt.primaries, ColorPrimaries::BT2020);
+        assert_eq!(fmt.xfer, TransferCharacteristic::BT2020Ten);
+        assert_eq!(fmt.matrix, MatrixCoefficients::BT2020NonConstantLuminance);
+        assert_eq!(
+            formaton_color(&fmt),
+            (Some(VPX_CS_BT_2020), Some(VPX_CR_FULL_RANGE))
+        );
+        assert_eq!(ImageFormat::from_formaton(&fmt), Some(f));
+
+        let fmt = f.color_formaton(VPX_CS_UNKNOWN, VPX_CR_STUDIO_RANGE);
+        assert_eq!(formaton_color(&fmt), (None, Some(VPX_CR_STUDIO_RANGE)));
+
+        assert_eq!(formaton_color(YUV420).0, None);
+    }
+
+    #[test]
+    fn check_planes() {
+        let f = ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap();
+        let (luma, chroma) = (vec![0u8; 16 * 15 + 15], vec![0u8; 8 * 7 + 8]);
+
+        assert!(f
+            .check_planes(15, 16, &[&luma, &chroma, &chroma], &[16, 8, 8])
+            .is_ok());
+        assert!(f
+            .check_planes(15, 16, &[&luma, &chroma, &chroma[1..]], &[16, 8, 8])
+            .is_err());
+        assert!(f
+            .check_planes(17, 16, &[&luma, &chroma, &chroma], &[16, 8, 8])
+            .is_err());
+        assert!(f.check_planes(15, 16, &[&luma, &chroma], &[16, 8]).is_err());
+    }
+}
@@ -2,64 +2,761 @@
 //!
 //!
 
-use crate::common::VPXCodec;
+use crate::common::{ImageFormat, VPXCodec, VPXCodecId};
 use crate::ffi::*;
+use crate::image::Image;
+use crate::pool::{self, FramePool, PooledBuffer, Storage};
+use crate::vpcc::VPCodecConfigurationRecord;
 
-use std::mem::MaybeUninit;
+use std::collections::VecDeque;
+use std::mem::{self, MaybeUninit};
 use std::ptr;
 use std::sync::Arc;
 
-use av_data::frame::{Frame, FrameBufferCopy, FrameType, VideoInfo};
-use av_data::pixel::formats::YUV420;
-
 use self::vpx_codec_err_t::*;
 
-fn frame_from_img(img: vpx_image_t) -> Frame {
-    use self::vpx_img_fmt_t::*;
+/// How the decoding of a picture went
+///
+/// Useful to request a keyframe only when the picture cannot be trusted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FrameInfo {
+    /// The picture or one of its references is damaged,
+    /// `VP8D_GET_FRAME_CORRUPTED`
+    pub corrupted: bool,
+    /// Mask of the reference buffers updated by the packet the picture
+    /// belongs to, `VP8D_GET_LAST_REF_UPDATES`
+    pub ref_updates: u32,
+    /// The picture is corrupted while error concealment is enabled
+    ///
+    /// libvpx does not report whether concealment actually ran, this is
+    /// `corrupted` combined with [`VP9DecoderConfig::error_concealment`],
+    /// which only the VP8 decoder implements.
+    pub concealed: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Plane {
+    offset: usize,
+    stride: usize,
+}
+
+/// Decoded picture owned by the caller
+///
+/// Depending on the decoder configuration the samples are either copied
+/// or shared with the decoder, see [`VP9DecoderConfig::frame_buffer_pool`].
+#[derive(Debug)]
+pub struct DecodedFrame {
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    render_size: (u32, u32),
+    color_space: vpx_color_space_t,
+    color_range: vpx_color_range_t,
+    info: FrameInfo,
+    storage: Storage,
+    planes: [Plane; 3],
+}
+
+impl DecodedFrame {
+    /// Copy the visible area of a decoded picture
+    fn copy_from(img: &DecodedImage<'_>) -> DecodedFrame {
+        let mut data = Vec::new();
+        let mut planes = [Plane::default(); 3];
+
+        for (idx, plane) in planes.iter_mut().enumerate() {
+            let (w, _) = img.plane_size(idx);
+
+            plane.offset = data.len();
+            plane.stride = w * img.format.bytes_per_sample();
+
+            for row in img.rows(idx) {
+                data.extend_from_slice(row);
+            }
+        }
+
+        DecodedFrame {
+            format: img.format,
+            width: img.width(),
+            height: img.height(),
+            render_size: img.render_size(),
+            color_space: img.color_space(),
+            color_range: img.color_range(),
+            info: img.info,
+            storage: Storage::Owned(data.into_boxed_slice()),
+            planes,
+        }
+    }
+
+    /// Wrap a picture decoded in a pooled buffer
+    ///
+    /// Returns `None` if the planes are not where they are expected to be.
+    fn from_pooled(img: &DecodedImage<'_>, buf: Arc<PooledBuffer>) -> Option<DecodedFrame> {
+        let storage = Storage::Shared(buf);
+        let data = storage.as_slice();
+        let base = data.as_ptr() as usize;
+        let mut planes = [Plane::default(); 3];
+
+        for (idx, plane) in planes.iter_mut().enumerate() {
+            let start = img.img.planes[idx] as usize;
+            let len = img.plane(idx).len();
+
+            plane.offset = start.checked_sub(base)?;
+            plane.stride = img.stride(idx);
+
+            if plane.offset.checked_add(len)? > data.len() {
+                return None;
+            }
+        }
+
+        Some(DecodedFrame {
+            format: img.format,
+            width: img.width(),
+            height: img.height(),
+            render_size: img.render_size(),
+            color_space: img.color_space(),
+            color_range: img.color_range(),
+            info: img.info,
+            storage,
+            planes,
+        })
+    }
+
+    /// Displayed width
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Displayed height
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Size the picture is meant to be displayed at, as `(width, height)`
+    pub fn render_size(&self) -> (u32, u32) {
+        self.render_size
+    }
+
+    /// Layout of the samples
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Color space signalled in the bitstream
+    pub fn color_space(&self) -> vpx_color_space_t {
+        self.color_space
+    }
+
+    /// Color range signalled in the bitstream
+    pub fn color_range(&self) -> vpx_color_range_t {
+        self.color_range
+    }
+
+    /// How the decoding went
+    pub fn info(&self) -> FrameInfo {
+        self.info
+    }
+
+    /// Number of planes
+    pub fn plane_count(&self) -> usize {
+        self.planes.len()
+    }
+
+    /// Distance in bytes between two rows of the `idx`-th plane
+    pub fn stride(&self, idx: usize) -> usize {
+        self.planes[idx].stride
+    }
+
+    /// Width and height in samples of the `idx`-th plane
+    pub fn plane_size(&self, idx: usize) -> (usize, usize) {
+        self.format.plane_size(idx, self.width, self.height)
+    }
+
+    fn plane_range(&self, idx: usize) -> std::ops::Range<usize> {
+        let Plane { offset, stride } = self.planes[idx];
+        let (w, h) = self.plane_size(idx);
+        let row = w * self.format.bytes_per_sample();
+        let available = self.storage.as_slice().len() - offset;
+
+        // Whole rows when the buffer allows it.
+        let len = match h {
+            0 => 0,
+            _ if stride * h <= available => stride * h,
+            _ => stride * (h - 1) + row,
+        };
+
+        offset..offset + len
+    }
+
+    /// Samples of the `idx`-th plane
+    ///
+    /// High bit depth samples are native endian `u16`s.
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is not lower than [`plane_count`].
+    ///
+    /// [`plane_count`]: #method.plane_count
+    pub fn plane(&self, idx: usize) -> &[u8] {
+        let range = self.plane_range(idx);
+
+        &self.storage.as_slice()[range]
+    }
+
+    /// Mutable samples of the `idx`-th plane
+    ///
+    /// The samples are copied first if they are still shared with the
+    /// decoder.
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is not lower than [`plane_count`].
+    ///
+    /// [`plane_count`]: #method.plane_count
+    pub fn plane_mut(&mut self, idx: usize) -> &mut [u8] {
+        let range = self.plane_range(idx);
+
+        &mut self.storage.as_mut_slice()[range]
+    }
+
+    /// Visible rows of the `idx`-th plane
+    pub fn rows(&self, idx: usize) -> impl Iterator<Item = &[u8]> {
+        let data = self.plane(idx);
+        let (w, h) = self.plane_size(idx);
+        let stride = self.stride(idx);
+        let row = w * self.format.bytes_per_sample();
+
+        (0..h).map(move |y| &data[y * stride..y * stride + row])
+    }
+}
+
+#[cfg(feature = "av-data")]
+mod frame_conv {
+    use super::*;
+    use av_data::frame::{Frame, FrameBuffer, FrameError, FrameType, MediaKind, VideoInfo};
+
+    impl FrameBuffer for DecodedFrame {
+        fn linesize(&self, idx: usize) -> Result<usize, FrameError> {
+            if idx < self.plane_count() {
+                Ok(self.stride(idx))
+            } else {
+                Err(FrameError::InvalidIndex)
+            }
+        }
+
+        fn count(&self) -> usize {
+            self.plane_count()
+        }
+
+        fn as_slice_inner(&self, idx: usize) -> Result<&[u8], FrameError> {
+            if idx < self.plane_count() {
+                Ok(self.plane(idx))
+            } else {
+                Err(FrameError::InvalidIndex)
+            }
+        }
+
+        fn as_mut_slice_inner(&mut self, idx: usize) -> Result<&mut [u8], FrameError> {
+            if idx < self.plane_count() {
+                Ok(self.plane_mut(idx))
+            } else {
+                Err(FrameError::InvalidIndex)
+            }
+        }
+    }
+
+    impl From<DecodedFrame> for Frame {
+        /// Wrap the decoded picture, without copying the samples
+        fn from(f: DecodedFrame) -> Frame {
+            let video = VideoInfo::new(
+                f.width() as usize,
+                f.height() as usize,
+                false,
+                FrameType::OTHER,
+                Arc::new(f.format().color_formaton(f.color_space(), f.color_range())),
+            );
+
+            Frame {
+                kind: MediaKind::Video(video),
+                buf: Box::new(f),
+                t: Default::default(),
+            }
+        }
+    }
+}
+
+/// Basic information about a compressed stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub width: u32,
+    pub height: u32,
+    pub is_keyframe: bool,
+}
+
+impl StreamInfo {
+    fn new(si: vpx_codec_stream_info_t) -> StreamInfo {
+        StreamInfo {
+            width: si.w,
+            height: si.h,
+            is_keyframe: si.is_kf != 0,
+        }
+    }
+}
 
-    let format = match img.fmt {
-        VPX_IMG_FMT_I420 => YUV420,
-        _ => panic!("TODO: support more pixel formats"),
+fn empty_stream_info() -> vpx_codec_stream_info_t {
+    vpx_codec_stream_info_t {
+        sz: mem::size_of::<vpx_codec_stream_info_t>() as u32,
+        w: 0,
+        h: 0,
+        is_kf: 0,
+    }
+}
+
+/// Parse the stream information from a compressed frame
+///
+/// No decoder is needed, so it can be used to size buffers or reject
+/// unsupported streams beforehand.
+///
+/// It matches a call to `vpx_codec_peek_stream_info`.
+pub fn peek_stream_info(codec: VPXCodecId, data: &[u8]) -> Result<StreamInfo, vpx_codec_err_t> {
+    let mut si = empty_stream_info();
+
+    let ret = unsafe {
+        vpx_codec_peek_stream_info(
+            codec.decoder_iface(),
+            data.as_ptr(),
+            data.len() as u32,
+            &mut si,
+        )
     };
-    let video = VideoInfo::new(
-        img.d_w as usize,
-        img.d_h as usize,
-        false,
-        FrameType::OTHER,
-        Arc::new(*format),
-    );
-
-    let mut frame = Frame::new_default_frame(video, None);
-
-    let src = img
-        .planes
-        .iter()
-        .zip(img.stride.iter())
-        .zip(format.iter())
-        .map(|((plane, line), chromaton)| unsafe {
-            std::slice::from_raw_parts(
-                *plane as *const u8,
-                *line as usize * chromaton.map(|c| c.get_height(img.h as usize)).unwrap_or(0),
+
+    match ret {
+        VPX_CODEC_OK => Ok(StreamInfo::new(si)),
+        _ => Err(ret),
+    }
+}
+
+/// Decoded picture borrowed from the decoder
+///
+/// It is valid until the decoder is used again.
+#[derive(Clone, Copy, Debug)]
+pub struct DecodedImage<'a> {
+    img: &'a vpx_image_t,
+    format: ImageFormat,
+    info: FrameInfo,
+}
+
+impl<'a> DecodedImage<'a> {
+    /// Displayed width
+    pub fn width(&self) -> u32 {
+        self.img.d_w
+    }
+
+    /// Displayed height
+    pub fn height(&self) -> u32 {
+        self.img.d_h
+    }
+
+    /// Size the picture is meant to be displayed at, as `(width, height)`
+    ///
+    /// It is the render size signalled in the bitstream, the coded size
+    /// if there is none.
+    pub fn render_size(&self) -> (u32, u32) {
+        match (self.img.r_w, self.img.r_h) {
+            (0, _) | (_, 0) => (self.img.d_w, self.img.d_h),
+            size => size,
+        }
+    }
+
+    /// libvpx pixel format
+    pub fn fmt(&self) -> vpx_img_fmt_t {
+        self.img.fmt
+    }
+
+    /// Layout of the samples
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Bits per sample
+    pub fn bit_depth(&self) -> u32 {
+        self.img.bit_depth
+    }
+
+    /// Tell whether the samples are stored in 16 bits
+    pub fn is_high_bitdepth(&self) -> bool {
+        self.format.is_high_bitdepth()
+    }
+
+    /// Horizontal and vertical chroma subsampling, as shifts
+    pub fn chroma_shift(&self) -> (u32, u32) {
+        (self.img.x_chroma_shift, self.img.y_chroma_shift)
+    }
+
+    /// Color space signalled in the bitstream
+    pub fn color_space(&self) -> vpx_color_space_t {
+        self.img.cs
+    }
+
+    /// Color range signalled in the bitstream
+    pub fn color_range(&self) -> vpx_color_range_t {
+        self.img.range
+    }
+
+    /// How the decoding went
+    pub fn info(&self) -> FrameInfo {
+        self.info
+    }
+
+    /// Number of planes
+    pub fn plane_count(&self) -> usize {
+        3
+    }
+
+    /// Distance in bytes between two rows of the `idx`-th plane
+    pub fn stride(&self, idx: usize) -> usize {
+        self.img.stride[idx] as usize
+    }
+
+    /// Width and height in samples of the `idx`-th plane
+    pub fn plane_size(&self, idx: usize) -> (usize, usize) {
+        self.format.plane_size(idx, self.img.d_w, self.img.d_h)
+    }
+
+    /// Samples of the `idx`-th plane, up to the end of the last row
+    ///
+    /// High bit depth samples are native endian `u16`s.
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is not lower than [`plane_count`].
+    ///
+    /// [`plane_count`]: #method.plane_count
+    pub fn plane(&self, idx: usize) -> &'a [u8] {
+        assert!(idx < self.plane_count());
+
+        let (w, h) = self.plane_size(idx);
+        let len = match h {
+            0 => 0,
+            _ => self.stride(idx) * (h - 1) + w * self.format.bytes_per_sample(),
+        };
+
+        unsafe { std::slice::from_raw_parts(self.img.planes[idx], len) }
+    }
+
+    /// Visible rows of the `idx`-th plane
+    pub fn rows(&self, idx: usize) -> impl Iterator<Item = &'a [u8]> {
+        let data = self.plane(idx);
+        let (w, h) = self.plane_size(idx);
+        let stride = self.stride(idx);
+        let row = w * self.format.bytes_per_sample();
+
+        (0..h).map(move |y| &data[y * stride..y * stride + row])
+    }
+
+    /// Copy the picture in a new `DecodedFrame`
+    pub fn to_frame(&self) -> DecodedFrame {
+        DecodedFrame::copy_from(self)
+    }
+
+    /// Copy the picture in a new `Image`
+    ///
+    /// The strides of the copy are aligned to `align` bytes.
+    pub fn to_image(&self, align: u32) -> Result<Image, vpx_codec_err_t> {
+        let mut img = Image::new(self.format, self.width(), self.height(), align)?;
+        img.set_color(self.color_space(), self.color_range());
+
+        for idx in 0..self.plane_count() {
+            for (dst, src) in img.rows_mut(idx).zip(self.rows(idx)) {
+                dst.copy_from_slice(src);
+            }
+        }
+
+        Ok(img)
+    }
+}
+
+/// Post-processing applied to the decoded pictures
+///
+/// It requires a decoder created with [`VP9DecoderConfig::postproc`] and
+/// maps to `vp8_postproc_cfg_t`.
+///
+/// [`VP9DecoderConfig::postproc`]: struct.VP9DecoderConfig.html#structfield.postproc
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PostProcConfig {
+    /// `VP8_DEBLOCK`
+    pub deblock: bool,
+    /// `VP8_DEMACROBLOCK`
+    pub demacroblock: bool,
+    /// `VP8_ADDNOISE`
+    pub add_noise: bool,
+    /// `VP8_MFQE`, multiframe quality enhancement
+    pub mfqe: bool,
+    /// Strength of the deblocking and demacroblocking filters
+    pub deblocking_level: u8,
+    /// Amount of noise added
+    pub noise_level: u8,
+}
+
+impl PostProcConfig {
+    fn to_raw(self) -> vp8_postproc_cfg_t {
+        use self::vp8_postproc_level::*;
+
+        let mut flags = 0;
+
+        if self.deblock {
+            flags |= VP8_DEBLOCK as i32;
+        }
+        if self.demacroblock {
+            flags |= VP8_DEMACROBLOCK as i32;
+        }
+        if self.add_noise {
+            flags |= VP8_ADDNOISE as i32;
+        }
+        if self.mfqe {
+            flags |= VP8_MFQE as i32;
+        }
+
+        vp8_postproc_cfg_t {
+            post_proc_flag: flags,
+            deblocking_level: self.deblocking_level.into(),
+            noise_level: self.noise_level.into(),
+        }
+    }
+}
+
+/// VP9 Decoder setup facility
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VP9DecoderConfig {
+    /// Codec of the stream, VP9 by default
+    pub codec: VPXCodecId,
+    /// Maximum number of threads to use, `0` lets libvpx pick
+    pub threads: u32,
+    /// Width hint, `0` if unknown
+    pub width: u32,
+    /// Height hint, `0` if unknown
+    pub height: u32,
+    /// `VPX_CODEC_USE_POSTPROC`, implied by `postproc_config`
+    pub postproc: bool,
+    /// Post-processing applied from the start
+    pub postproc_config: Option<PostProcConfig>,
+    /// `VPX_CODEC_USE_ERROR_CONCEALMENT`
+    pub error_concealment: bool,
+    /// `VPX_CODEC_USE_INPUT_FRAGMENTS`
+    pub input_fragments: bool,
+    /// `VPX_CODEC_USE_FRAME_THREADING`
+    pub frame_threading: bool,
+    /// Decode into reference counted buffers shared with the output
+    /// frames instead of copying every picture
+    pub frame_buffer_pool: bool,
+}
+
+impl Default for VP9DecoderConfig {
+    fn default() -> Self {
+        VP9DecoderConfig::new()
+    }
+}
+
+impl VP9DecoderConfig {
+    /// Create a new default configuration
+    pub const fn new() -> VP9DecoderConfig {
+        VP9DecoderConfig {
+            codec: VPXCodecId::VP9,
+            threads: 0,
+            width: 0,
+            height: 0,
+            postproc: false,
+            postproc_config: None,
+            error_concealment: false,
+            input_fragments: false,
+            frame_threading: false,
+            frame_buffer_pool: false,
+        }
+    }
+
+    /// Return a newly allocated `VP9Decoder` using the current configuration
+    pub fn get_decoder<T>(&self) -> Result<VP9Decoder<T>, vpx_codec_err_t> {
+        VP9Decoder::with_config(self)
+    }
+
+    fn flags(&self) -> vpx_codec_flags_t {
+        let mut flags = 0;
+
+        if self.postproc || self.postproc_config.is_some() {
+            flags |= VPX_CODEC_USE_POSTPROC;
+        }
+        if self.error_concealment {
+            flags |= VPX_CODEC_USE_ERROR_CONCEALMENT;
+        }
+        if self.input_fragments {
+            flags |= VPX_CODEC_USE_INPUT_FRAGMENTS;
+        }
+        if self.frame_threading {
+            flags |= VPX_CODEC_USE_FRAME_THREADING;
+        }
+
+        flags as vpx_codec_flags_t
+    }
+
+    fn init_context(&self) -> Result<vpx_codec_ctx, vpx_codec_err_t> {
+        let mut ctx = MaybeUninit::uninit();
+        let cfg = vpx_codec_dec_cfg_t {
+            threads: self.threads,
+            w: self.width,
+            h: self.height,
+        };
+
+        let ret = unsafe {
+            vpx_codec_dec_init_ver(
+                ctx.as_mut_ptr(),
+                self.codec.decoder_iface(),
+                &cfg,
+                self.flags(),
+                VPX_DECODER_ABI_VERSION as i32,
             )
-        });
+        };
 
-    let linesize = img.stride.iter().map(|line| *line as usize);
+        match ret {
+            VPX_CODEC_OK => Ok(unsafe { ctx.assume_init() }),
+            _ => Err(ret),
+        }
+    }
+}
+
+/// Select the post-processing filters
+///
+/// It calls `vpx_codec_control_` with `VP8_SET_POSTPROC`.
+fn set_postproc(ctx: &mut vpx_codec_ctx, cfg: &PostProcConfig) -> Result<(), vpx_codec_err_t> {
+    let mut raw = cfg.to_raw();
+    let ret = unsafe {
+        vpx_codec_control_(
+            ctx,
+            vp8_com_control_id::VP8_SET_POSTPROC as i32,
+            &mut raw as *mut vp8_postproc_cfg_t,
+        )
+    };
 
-    frame.copy_from_slice(src, linesize);
-    frame
+    match ret {
+        VPX_CODEC_OK => Ok(()),
+        _ => Err(ret),
+    }
+}
+
+/// Make libvpx allocate its frame buffers from `pool`
+///
+/// It calls `vpx_codec_set_frame_buffer_functions`, it must happen
+/// before the first `vpx_codec_decode`.
+fn set_frame_buffer_pool(
+    ctx: &mut vpx_codec_ctx,
+    pool: &Arc<FramePool>,
+) -> Result<(), vpx_codec_err_t> {
+    let ret = unsafe {
+        vpx_codec_set_frame_buffer_functions(
+            ctx,
+            Some(pool::get_frame_buffer),
+            Some(pool::release_frame_buffer),
+            Arc::as_ptr(pool) as *mut _,
+        )
+    };
+
+    match ret {
+        VPX_CODEC_OK => Ok(()),
+        _ => Err(ret),
+    }
 }
 
-use std::marker::PhantomData;
+/// Private data of the packets being decoded
+///
+/// libvpx only carries an opaque `user_priv` pointer from a packet to the
+/// picture it produces, it never frees it and may return it for more than
+/// one picture or for none. A token is passed instead and the data stays
+/// here until it is handed out or dropped.
+#[derive(Debug)]
+struct PrivateData<T> {
+    // Sorted by token, libvpx outputs the pictures in decoding order.
+    pending: VecDeque<(usize, Box<T>)>,
+    next: usize,
+}
+
+impl<T> PrivateData<T> {
+    fn new() -> PrivateData<T> {
+        PrivateData {
+            pending: VecDeque::new(),
+            next: 1,
+        }
+    }
+
+    /// Store `data` and return the token to pass as `user_priv`
+    fn insert(&mut self, data: T) -> usize {
+        let token = self.next;
+        // 0 is the null pointer, meaning no private data.
+        self.next = self.next.checked_add(1).unwrap_or(1);
+        self.pending.push_back((token, Box::new(data)));
+
+        token
+    }
+
+    /// Take the data of the packet that produced a picture
+    ///
+    /// The data of the packets decoded before it is dropped, they cannot
+    /// produce any picture anymore.
+    fn take(&mut self, token: usize) -> Option<Box<T>> {
+        while let Some(&(t, _)) = self.pending.front() {
+            if t == token {
+                return self.pending.pop_front().map(|(_, data)| data);
+            } else if t > token {
+                return None;
+            }
+            self.pending.pop_front();
+        }
+
+        None
+    }
+
+    /// Drop the data of a packet the decoder rejected
+    fn remove(&mut self, token: usize) {
+        if let Some(idx) = self.pending.iter().rposition(|&(t, _)| t == token) {
+            self.pending.remove(idx);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
 
 /// VP9 Decoder
+///
+/// It is `Send` and `Sync` as long as the private data is.
+///
+/// ```compile_fail
+/// fn is_send<T: Send>() {}
+/// is_send::<libvpx::decoder::VP9Decoder<std::rc::Rc<()>>>();
+/// ```
+///
+/// ```compile_fail
+/// fn is_sync<T: Sync>() {}
+/// is_sync::<libvpx::decoder::VP9Decoder<std::cell::Cell<()>>>();
+/// ```
 pub struct VP9Decoder<T> {
     pub(crate) ctx: vpx_codec_ctx,
     pub(crate) iter: vpx_codec_iter_t,
-    private_data: PhantomData<T>,
+    pub(crate) cfg: VP9DecoderConfig,
+    pub(crate) codec_config: Option<VPCodecConfigurationRecord>,
+    // Layout and color information of the last picture
+    pub(crate) format: Option<(ImageFormat, vpx_color_space_t, vpx_color_range_t)>,
+    // Must outlive `ctx`, libvpx releases the buffers it holds on destroy.
+    pool: Option<Arc<FramePool>>,
+    private_data: PrivateData<T>,
+    #[cfg(feature = "codec-trait")]
+    timing: decoder_trait::Timing,
 }
 
-unsafe impl<T: Send> Send for VP9Decoder<T> {} // TODO: Make sure it cannot be abused
-unsafe impl<T: Sync> Sync for VP9Decoder<T> {} // TODO: Make sure it cannot be abused
+// libvpx contexts are not bound to the thread that created them, the frame
+// buffer pool is shared through `Arc` and the private data moves with the
+// decoder. Every method reaching the context or the private data takes
+// `&mut self`, `&self` only reads plain configuration.
+unsafe impl<T: Send> Send for VP9Decoder<T> {}
+unsafe impl<T: Sync> Sync for VP9Decoder<T> {}
 
 impl<T> VP9Decoder<T> {
     /// Create a new decoder
@@ -69,32 +766,309 @@ impl<T> VP9Decoder<T> {
     /// The function may fail if the underlying libvpx does not provide
     /// the VP9 decoder.
     pub fn new() -> Result<VP9Decoder<T>, vpx_codec_err_t> {
-        let mut ctx = MaybeUninit::uninit();
-        let cfg = MaybeUninit::zeroed();
+        VP9Decoder::with_config(&VP9DecoderConfig::new())
+    }
 
-        let ret = unsafe {
-            vpx_codec_dec_init_ver(
-                ctx.as_mut_ptr(),
-                vpx_codec_vp9_dx(),
-                cfg.as_ptr(),
-                0,
-                VPX_DECODER_ABI_VERSION as i32,
-            )
+    /// Create a new decoder using the provided configuration
+    ///
+    /// You may use `get_decoder` instead.
+    ///
+    /// # Errors
+    ///
+    /// Beside the reasons listed in [`new`], it fails with
+    /// `VPX_CODEC_INCAPABLE` if a requested feature is not supported,
+    /// e.g. error concealment is only available in VP8.
+    ///
+    /// [`new`]: #method.new
+    pub fn with_config(cfg: &VP9DecoderConfig) -> Result<VP9Decoder<T>, vpx_codec_err_t> {
+        let mut dec = VP9Decoder {
+            ctx: cfg.init_context()?,
+            iter: ptr::null(),
+            cfg: *cfg,
+            codec_config: None,
+            format: None,
+            pool: None,
+            private_data: PrivateData::new(),
+            #[cfg(feature = "codec-trait")]
+            timing: Default::default(),
         };
 
-        match ret {
-            VPX_CODEC_OK => {
-                let ctx = unsafe { ctx.assume_init() };
-                Ok(VP9Decoder {
-                    ctx,
-                    iter: ptr::null(),
-                    private_data: PhantomData,
-                })
+        if cfg.frame_buffer_pool {
+            let pool = Arc::default();
+            set_frame_buffer_pool(&mut dec.ctx, &pool)?;
+            dec.pool = Some(pool);
+        }
+
+        if let Some(ref postproc) = cfg.postproc_config {
+            set_postproc(&mut dec.ctx, postproc)?;
+        }
+
+        Ok(dec)
+    }
+
+    /// Recreate the underlying libvpx decoder with a new configuration
+    ///
+    /// Any decoding state is lost, the stream must restart from a keyframe.
+    /// On failure the current decoder is left untouched.
+    pub fn reconfigure(&mut self, cfg: &VP9DecoderConfig) -> Result<(), vpx_codec_err_t> {
+        let mut ctx = cfg.init_context()?;
+
+        let pool = if cfg.frame_buffer_pool {
+            let pool = self.pool.clone().unwrap_or_default();
+            if let Err(err) = set_frame_buffer_pool(&mut ctx, &pool) {
+                unsafe { vpx_codec_destroy(&mut ctx) };
+                return Err(err);
+            }
+            Some(pool)
+        } else {
+            None
+        };
+
+        if let Some(ref postproc) = cfg.postproc_config {
+            if let Err(err) = set_postproc(&mut ctx, postproc) {
+                unsafe { vpx_codec_destroy(&mut ctx) };
+                return Err(err);
             }
+        }
+
+        unsafe { vpx_codec_destroy(&mut self.ctx) };
+        self.ctx = ctx;
+        self.iter = ptr::null();
+        self.cfg = *cfg;
+        self.pool = pool;
+        self.private_data.clear();
+        #[cfg(feature = "codec-trait")]
+        {
+            self.timing = Default::default();
+        }
+
+        Ok(())
+    }
+
+    /// Return the configuration in use
+    pub fn config(&self) -> &VP9DecoderConfig {
+        &self.cfg
+    }
+
+    /// Update the decoder parameters
+    ///
+    /// The typed methods below cover the controls currently defined by
+    /// libvpx.
+    ///
+    /// It calls `vpx_codec_control_`
+    pub fn control(&mut self, id: vp8_dec_control_id, val: i32) -> Result<(), vpx_codec_err_t> {
+        let ret = unsafe { vpx_codec_control_(&mut self.ctx, id as i32, val) };
+
+        match ret {
+            VPX_CODEC_OK => Ok(()),
+            _ => Err(ret),
+        }
+    }
+
+    fn control_get<V: Default>(&mut self, id: vp8_dec_control_id) -> Result<V, vpx_codec_err_t> {
+        let mut val = V::default();
+        let ret = unsafe { vpx_codec_control_(&mut self.ctx, id as i32, &mut val as *mut V) };
+
+        match ret {
+            VPX_CODEC_OK => Ok(val),
             _ => Err(ret),
         }
     }
 
+    /// Change the post-processing applied to the next pictures
+    ///
+    /// It fails with `VPX_CODEC_INCAPABLE` if the decoder was not created
+    /// with post-processing enabled, or if libvpx was built without it.
+    ///
+    /// It calls `vpx_codec_control_` with `VP8_SET_POSTPROC`.
+    pub fn set_postproc(&mut self, cfg: &PostProcConfig) -> Result<(), vpx_codec_err_t> {
+        if !self.cfg.postproc && self.cfg.postproc_config.is_none() {
+            return Err(VPX_CODEC_INCAPABLE);
+        }
+
+        set_postproc(&mut self.ctx, cfg)?;
+        self.cfg.postproc_config = Some(*cfg);
+
+        Ok(())
+    }
+
+    /// Skip the loop filter, trading quality for speed
+    ///
+    /// Useful e.g. for thumbnailing. It maps to `VP9_SET_SKIP_LOOP_FILTER`.
+    pub fn set_skip_loop_filter(&mut self, skip: bool) -> Result<(), vpx_codec_err_t> {
+        self.control(vp8_dec_control_id::VP9_SET_SKIP_LOOP_FILTER, skip as i32)
+    }
+
+    /// Set the byte alignment of the decoded frame planes
+    ///
+    /// It must be `0` (legacy alignment) or a power of two between 32 and
+    /// 1024. It maps to `VP9_SET_BYTE_ALIGNMENT`.
+    pub fn set_byte_alignment(&mut self, align: i32) -> Result<(), vpx_codec_err_t> {
+        self.control(vp8_dec_control_id::VP9_SET_BYTE_ALIGNMENT, align)
+    }
+
+    /// Decode the tiles in reverse order
+    ///
+    /// It maps to `VP9_INVERT_TILE_DECODE_ORDER`.
+    pub fn set_invert_tile_decode_order(&mut self, invert: bool) -> Result<(), vpx_codec_err_t> {
+        self.control(
+            vp8_dec_control_id::VP9_INVERT_TILE_DECODE_ORDER,
+            invert as i32,
+        )
+    }
+
+    /// Decode up to the given spatial layer of a SVC stream
+    ///
+    /// It maps to `VP9_DECODE_SVC_SPATIAL_LAYER`.
+    ///
+    /// Available with libvpx 1.7 or later.
+    #[cfg(vpx_1_7)]
+    pub fn set_svc_spatial_layer(&mut self, layer: i32) -> Result<(), vpx_codec_err_t> {
+        self.control(vp8_dec_control_id::VP9_DECODE_SVC_SPATIAL_LAYER, layer)
+    }
+
+    /// Enable row based multi-threading
+    ///
+    /// It maps to `VP9D_SET_ROW_MT`.
+    ///
+    /// Available with libvpx 1.8 or later.
+    #[cfg(vpx_1_8)]
+    pub fn set_row_mt(&mut self, enable: bool) -> Result<(), vpx_codec_err_t> {
+        self.control(vp8_dec_control_id::VP9D_SET_ROW_MT, enable as i32)
+    }
+
+    /// Enable the optimized loop filter
+    ///
+    /// It maps to `VP9D_SET_LOOP_FILTER_OPT`.
+    ///
+    /// Available with libvpx 1.8 or later.
+    #[cfg(vpx_1_8)]
+    pub fn set_loop_filter_opt(&mut self, enable: bool) -> Result<(), vpx_codec_err_t> {
+        self.control(vp8_dec_control_id::VP9D_SET_LOOP_FILTER_OPT, enable as i32)
+    }
+
+    /// Return the mask of the reference frames updated by the last frame
+    ///
+    /// It maps to `VP8D_GET_LAST_REF_UPDATES`.
+    pub fn last_ref_updates(&mut self) -> Result<i32, vpx_codec_err_t> {
+        self.control_get(vp8_dec_control_id::VP8D_GET_LAST_REF_UPDATES)
+    }
+
+    /// Return the mask of the reference frames used by the last frame
+    ///
+    /// It maps to `VP8D_GET_LAST_REF_USED`.
+    pub fn last_ref_used(&mut self) -> Result<i32, vpx_codec_err_t> {
+        self.control_get(vp8_dec_control_id::VP8D_GET_LAST_REF_USED)
+    }
+
+    /// Tell whether the last decoded frame is corrupted
+    ///
+    /// It maps to `VP8D_GET_FRAME_CORRUPTED`.
+    pub fn frame_corrupted(&mut self) -> Result<bool, vpx_codec_err_t> {
+        self.control_get::<i32>(vp8_dec_control_id::VP8D_GET_FRAME_CORRUPTED)
+            .map(|v| v != 0)
+    }
+
+    /// Return the quantizer used by the last decoded frame
+    ///
+    /// It maps to `VPXD_GET_LAST_QUANTIZER`.
+    ///
+    /// Available with libvpx 1.8 or later.
+    #[cfg(vpx_1_8)]
+    pub fn last_quantizer(&mut self) -> Result<i32, vpx_codec_err_t> {
+        self.control_get(vp8_dec_control_id::VPXD_GET_LAST_QUANTIZER)
+    }
+
+    /// Return the display size signalled in the stream as `(width, height)`
+    ///
+    /// It maps to `VP9D_GET_DISPLAY_SIZE`.
+    pub fn display_size(&mut self) -> Result<(u32, u32), vpx_codec_err_t> {
+        self.control_get::<[i32; 2]>(vp8_dec_control_id::VP9D_GET_DISPLAY_SIZE)
+            .map(|[w, h]| (w as u32, h as u32))
+    }
+
+    /// Return the size of the last decoded frame as `(width, height)`
+    ///
+    /// It maps to `VP9D_GET_FRAME_SIZE`.
+    pub fn frame_size(&mut self) -> Result<(u32, u32), vpx_codec_err_t> {
+        self.control_get::<[i32; 2]>(vp8_dec_control_id::VP9D_GET_FRAME_SIZE)
+            .map(|[w, h]| (w as u32, h as u32))
+    }
+
+    /// Return the bit depth of the stream
+    ///
+    /// It maps to `VP9D_GET_BIT_DEPTH`.
+    pub fn bit_depth(&mut self) -> Result<u32, vpx_codec_err_t> {
+        self.control_get(vp8_dec_control_id::VP9D_GET_BIT_DEPTH)
+    }
+
+    /// Set the codec configuration record the stream is expected to match
+    ///
+    /// It is usually parsed from the container extradata.
+    pub fn set_codec_config(&mut self, record: Option<VPCodecConfigurationRecord>) {
+        self.codec_config = record;
+    }
+
+    /// Return the codec configuration record the stream is expected to match
+    pub fn codec_config(&self) -> Option<&VPCodecConfigurationRecord> {
+        self.codec_config.as_ref()
+    }
+
+    /// Retrieve the information about the stream being decoded
+    ///
+    /// The dimensions are known once the first keyframe has been decoded.
+    ///
+    /// It matches a call to `vpx_codec_get_stream_info`.
+    pub fn stream_info(&mut self) -> Result<StreamInfo, vpx_codec_err_t> {
+        let mut si = empty_stream_info();
+
+        let ret = unsafe { vpx_codec_get_stream_info(&mut self.ctx, &mut si) };
+
+        match ret {
+            VPX_CODEC_OK => Ok(StreamInfo::new(si)),
+            _ => Err(ret),
+        }
+    }
+
+    /// Report the parameters of the stream being decoded
+    ///
+    /// The dimensions come from [`stream_info`], `None` is returned until
+    /// the first keyframe has been decoded.
+    ///
+    /// The pixel format is the one of the last frame retrieved.
+    ///
+    /// [`stream_info`]: #method.stream_info
+    #[cfg(feature = "av-data")]
+    pub fn get_params(&mut self) -> Option<av_data::params::CodecParams> {
+        use av_data::params;
+
+        let si = self
+            .stream_info()
+            .ok()
+            .filter(|si| si.width != 0 && si.height != 0)?;
+
+        Some(params::CodecParams {
+            kind: Some(params::MediaKind::Video(params::VideoInfo {
+                width: si.width as usize,
+                height: si.height as usize,
+                format: self
+                    .format
+                    .map(|(f, cs, range)| Arc::new(f.color_formaton(cs, range))),
+            })),
+            codec_id: Some(
+                match self.cfg.codec {
+                    VPXCodecId::VP8 => "vp8",
+                    VPXCodecId::VP9 => "vp9",
+                }
+                .to_owned(),
+            ),
+            extradata: self.codec_config.map(|record| record.to_bytes()),
+            bit_rate: 0,
+            convergence_window: 0,
+            delay: 0,
+        })
+    }
+
     /// Feed some compressed data to the encoder
     ///
     /// The `data` slice is sent to the decoder alongside the optional
@@ -103,6 +1077,10 @@ impl<T> VP9Decoder<T> {
     /// The [`get_frame`] method must be called to retrieve the decompressed
     /// frame, do not call this method again before calling [`get_frame`].
     ///
+    /// `private` is returned with the picture the packet produces. It is
+    /// dropped if the packet produces none, once a later picture is
+    /// returned or when the decoder is dropped.
+    ///
     /// It matches a call to `vpx_codec_decode`.
     ///
     /// [`get_frame`]: #method.get_frame
@@ -110,16 +1088,16 @@ impl<T> VP9Decoder<T> {
     where
         O: Into<Option<T>>,
     {
-        let priv_data = private
+        let token = private
             .into()
-            .map(|v| Box::into_raw(Box::new(v)))
-            .unwrap_or(ptr::null_mut());
+            .map(|v| self.private_data.insert(v))
+            .unwrap_or(0);
         let ret = unsafe {
             vpx_codec_decode(
                 &mut self.ctx,
                 data.as_ptr(),
                 data.len() as u32,
-                priv_data as *mut std::ffi::c_void,
+                token as *mut std::ffi::c_void,
                 0,
             )
         };
@@ -130,9 +1108,7 @@ impl<T> VP9Decoder<T> {
         match ret {
             VPX_CODEC_OK => Ok(()),
             _ => {
-                if !priv_data.is_null() {
-                    let _ = unsafe { Box::from_raw(priv_data) };
-                }
+                self.private_data.remove(token);
                 Err(ret)
             }
         }
@@ -161,25 +1137,140 @@ impl<T> VP9Decoder<T> {
     ///
     /// Should be called repeatedly until it returns `None`.
     ///
+    /// With [`VP9DecoderConfig::frame_buffer_pool`] the frame points to
+    /// the buffer the picture was decoded into, which is recycled once
+    /// both the frame and libvpx are done with it.
+    ///
+    /// [`DecodedFrame::info`] tells whether the picture is damaged.
+    ///
+    /// It matches a call to `vpx_codec_get_frame`.
+    pub fn get_frame(&mut self) -> Option<(DecodedFrame, Option<Box<T>>)> {
+        let pooled = self.pool.is_some();
+        let (img, priv_data) = self.get_frame_ref()?;
+
+        // With the default allocator `fb_priv` belongs to libvpx.
+        let buf = match pooled {
+            true => unsafe { pool::image_buffer(img.img.fb_priv) },
+            false => None,
+        };
+        let frame = buf
+            .and_then(|buf| DecodedFrame::from_pooled(&img, buf))
+            .unwrap_or_else(|| img.to_frame());
+
+        Some((frame, priv_data))
+    }
+
+    /// Iterate over the pictures decoded so far
+    ///
+    /// The decoder is borrowed until the iterator is dropped.
+    ///
+    /// ```compile_fail
+    /// # fn f(dec: &mut libvpx::decoder::VP9Decoder<()>, data: &[u8]) {
+    /// for (frame, _) in dec.frames() {
+    ///     dec.decode(data, None).unwrap();
+    /// }
+    /// # }
+    /// ```
+    pub fn frames(&mut self) -> Frames<'_, T> {
+        Frames { dec: self }
+    }
+
+    /// Decode a sequence of packets, flush and return all the pictures
+    ///
+    /// Each packet comes with its optional private data.
+    #[allow(clippy::type_complexity)]
+    pub fn decode_all<D, I>(
+        &mut self,
+        packets: I,
+    ) -> Result<Vec<(DecodedFrame, Option<Box<T>>)>, vpx_codec_err_t>
+    where
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = (D, Option<T>)>,
+    {
+        let mut frames = Vec::new();
+
+        for (data, private) in packets {
+            self.decode(data.as_ref(), private)?;
+            frames.extend(self.frames());
+        }
+
+        self.flush()?;
+        frames.extend(self.frames());
+
+        Ok(frames)
+    }
+
+    /// Retrieve decoded pictures without copying them
+    ///
+    /// Should be called repeatedly until it returns `None`, the picture
+    /// must be dropped before using the decoder again.
+    ///
+    /// It also queries `VP8D_GET_FRAME_CORRUPTED` and
+    /// `VP8D_GET_LAST_REF_UPDATES` to fill [`DecodedImage::info`].
+    ///
+    /// A picture in a pixel format [`ImageFormat`] cannot describe is
+    /// skipped along with its private data, so `None` always means that
+    /// no picture is left.
+    ///
     /// It matches a call to `vpx_codec_get_frame`.
-    pub fn get_frame(&mut self) -> Option<(Frame, Option<Box<T>>)> {
+    pub fn get_frame_ref(&mut self) -> Option<(DecodedImage<'_>, Option<Box<T>>)> {
+        let (img, priv_data, format) = loop {
+            let (img, priv_data) = self.next_image()?;
+            let img = unsafe { &*img };
+
+            if let Some(format) = ImageFormat::from_image(img) {
+                break (img, priv_data, format);
+            }
+        };
+        let info = self.frame_info();
+
+        self.format = Some((format, img.cs, img.range));
+
+        Some((DecodedImage { img, format, info }, priv_data))
+    }
+
+    // The controls describe the last shown frame, the one just returned.
+    fn frame_info(&mut self) -> FrameInfo {
+        let corrupted = self.frame_corrupted().unwrap_or(false);
+
+        FrameInfo {
+            corrupted,
+            ref_updates: self.last_ref_updates().map_or(0, |mask| mask as u32),
+            // Inferred, libvpx has no control telling if concealment ran
+            concealed: corrupted && self.cfg.error_concealment,
+        }
+    }
+
+    fn next_image(&mut self) -> Option<(*const vpx_image_t, Option<Box<T>>)> {
         let img = unsafe { vpx_codec_get_frame(&mut self.ctx, &mut self.iter) };
         if img.is_null() {
             None
         } else {
-            let im = unsafe { *img };
-            let priv_data = if im.user_priv.is_null() {
-                None
-            } else {
-                let p = im.user_priv as *mut T;
-                Some(unsafe { Box::from_raw(p) })
+            let token = unsafe { (*img).user_priv } as usize;
+            let priv_data = match token {
+                0 => None,
+                _ => self.private_data.take(token),
             };
-            let frame = frame_from_img(im);
-            Some((frame, priv_data))
+            Some((img, priv_data))
         }
     }
 }
 
+/// Iterator over the decoded pictures, see [`VP9Decoder::frames`]
+///
+/// [`VP9Decoder::frames`]: struct.VP9Decoder.html#method.frames
+pub struct Frames<'a, T> {
+    dec: &'a mut VP9Decoder<T>,
+}
+
+impl<T> Iterator for Frames<'_, T> {
+    type Item = (DecodedFrame, Option<Box<T>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.dec.get_frame()
+    }
+}
+
 impl<T> Drop for VP9Decoder<T> {
     fn drop(&mut self) {
         unsafe { vpx_codec_destroy(&mut self.ctx) };
@@ -197,20 +1288,103 @@ mod decoder_trait {
     use super::*;
     use av_codec::decoder::*;
     use av_codec::error::*;
-    use av_data::frame::ArcFrame;
+    use av_data::frame::{ArcFrame, Frame};
     use av_data::packet::Packet;
     use av_data::timeinfo::TimeInfo;
+    use av_data::value::Value;
+    use std::collections::BTreeMap;
     use std::sync::Arc;
 
+    /// Timestamps of the packets sent and of the last frame returned
+    ///
+    /// A frame normally gets the `TimeInfo` of the packet it comes from,
+    /// the packets are tracked by pts to fill the gaps, e.g. when the
+    /// packet carried no timestamp.
+    #[derive(Debug, Default)]
+    pub(crate) struct Timing {
+        pending: BTreeMap<i64, TimeInfo>,
+        last: Option<TimeInfo>,
+    }
+
+    impl Timing {
+        pub(crate) fn sent(&mut self, t: &TimeInfo) {
+            if let Some(pts) = t.pts {
+                self.pending.insert(pts, t.clone());
+            }
+        }
+
+        /// Pick the timestamps of the next frame
+        pub(crate) fn received(&mut self, t: Option<TimeInfo>) -> TimeInfo {
+            let t = match t {
+                Some(t) if t.pts.is_some() => t,
+                // The earliest packet not shown yet
+                t => match self.pending.keys().next().copied() {
+                    Some(pts) => self.pending.remove(&pts).unwrap(),
+                    None => self.extrapolate(t.unwrap_or_default()),
+                },
+            };
+
+            // The frames are shown in pts order, the packets left behind
+            // were hidden frames.
+            if let Some(pts) = t.pts {
+                self.pending = match pts.checked_add(1) {
+                    Some(next) => self.pending.split_off(&next),
+                    None => BTreeMap::new(),
+                };
+            }
+            self.last = Some(t.clone());
+
+            t
+        }
+
+        fn extrapolate(&self, mut t: TimeInfo) -> TimeInfo {
+            if let Some(ref last) = self.last {
+                let duration = last.duration.unwrap_or(1);
+
+                t.pts = last.pts.and_then(|pts| pts.checked_add(duration as i64));
+                t.duration = t.duration.or(last.duration);
+                t.timebase = t.timebase.or(last.timebase);
+            }
+
+            t
+        }
+    }
+
     pub struct Des {
         descr: Descr,
+        cfg: VP9DecoderConfig,
+    }
+
+    impl Des {
+        /// Return a descriptor creating decoders with the provided configuration
+        ///
+        /// It describes a VP8 or VP9 decoder depending on `cfg.codec`. It is
+        /// the only way to pass a configuration through av-codec.
+        pub const fn with_config(cfg: VP9DecoderConfig) -> Des {
+            let descr = match cfg.codec {
+                VPXCodecId::VP8 => Descr {
+                    codec: "vp8",
+                    name: "vpx",
+                    desc: "libvpx VP8 decoder",
+                    mime: "video/VP8",
+                },
+                VPXCodecId::VP9 => Descr {
+                    codec: "vp9",
+                    name: "vpx",
+                    desc: "libvpx VP9 decoder",
+                    mime: "video/VP9",
+                },
+            };
+
+            Des { descr, cfg }
+        }
     }
 
     impl Descriptor for Des {
         type OutputDecoder = VP9Decoder<TimeInfo>;
 
         fn create(&self) -> Self::OutputDecoder {
-            VP9Decoder::new().unwrap()
+            self.cfg.get_decoder().unwrap()
         }
 
         fn describe(&self) -> &Descr {
@@ -219,44 +1393,79 @@ mod decoder_trait {
     }
 
     impl Decoder for VP9Decoder<TimeInfo> {
-        fn set_extradata(&mut self, _extra: &[u8]) {
-            // No-op
+        fn set_extradata(&mut self, extra: &[u8]) {
+            self.set_codec_config(VPCodecConfigurationRecord::from_bytes(extra));
         }
         fn send_packet(&mut self, pkt: &Packet) -> Result<()> {
             self.decode(&pkt.data, pkt.t.clone())
-                .map_err(|_err| unimplemented!())
+                .map_err(|_err| Error::InvalidData)?;
+            self.timing.sent(&pkt.t);
+
+            Ok(())
         }
         fn receive_frame(&mut self) -> Result<ArcFrame> {
-            self.get_frame()
-                .map(|(mut f, t)| {
-                    f.t = t.map(|b| *b).unwrap();
-                    Arc::new(f)
-                })
-                .ok_or(Error::MoreDataNeeded)
+            let (f, t) = self.get_frame().ok_or(Error::MoreDataNeeded)?;
+            let t = self.timing.received(t.map(|b| *b));
+
+            if let Some(record) = self.codec_config() {
+                if !record.matches_format(&f.format()) {
+                    return Err(Error::InvalidData);
+                }
+            }
+
+            let mut f = Frame::from(f);
+            f.t = t;
+            Ok(Arc::new(f))
         }
         fn flush(&mut self) -> Result<()> {
-            self.flush().map_err(|_err| unimplemented!())
+            self.flush().map_err(|_err| Error::InvalidData)
         }
         fn configure(&mut self) -> Result<()> {
             Ok(())
         }
     }
 
+    impl VP9DecoderConfig {
+        /// Set a configuration option from its av-codec key and value
+        ///
+        /// av-codec has no way to pass options to a decoder, the
+        /// configuration reaches it through [`Des::with_config`].
+        pub fn set_option(&mut self, key: &str, val: Value) -> Result<()> {
+            match (key, val) {
+                ("threads", Value::U64(v)) => self.threads = v as u32,
+                ("w", Value::U64(v)) => self.width = v as u32,
+                ("h", Value::U64(v)) => self.height = v as u32,
+                ("postproc", Value::Bool(v)) => self.postproc = v,
+                ("error-concealment", Value::Bool(v)) => self.error_concealment = v,
+                ("input-fragments", Value::Bool(v)) => self.input_fragments = v,
+                ("frame-threading", Value::Bool(v)) => self.frame_threading = v,
+                ("frame-buffer-pool", Value::Bool(v)) => self.frame_buffer_pool = v,
+                _ => return Err(Error::Unsupported(format!("{} key", key))),
+            }
+
+            Ok(())
+        }
+    }
+
     /// VP9 Decoder
     ///
     /// To be used with [av-codec](https://docs.rs/av-codec) `Context`.
-    pub const VP9_DESCR: &Des = &Des {
-        descr: Descr {
-            codec: "vp9",
-            name: "vpx",
-            desc: "libvpx VP9 decoder",
-            mime: "video/VP9",
-        },
-    };
+    ///
+    /// Use [`Des::with_config`] to create decoders with a different
+    /// configuration.
+    pub const VP9_DESCR: &Des = &Des::with_config(VP9DecoderConfig::new());
+
+    /// VP8 Decoder
+    ///
+    /// To be used with [av-codec](https://docs.rs/av-codec) `Context`.
+    pub const VP8_DESCR: &Des = &Des::with_config(VP9DecoderConfig {
+        codec: VPXCodecId::VP8,
+        ..VP9DecoderConfig::new()
+    });
 }
 
 #[cfg(feature = "codec-trait")]
-pub use self::decoder_trait::VP9_DESCR;
+pub use self::decoder_trait::{Des, VP8_DESCR, VP9_DESCR};
 
 #[cfg(test)]
 mod tests {
@@ -268,12 +1477,192 @@ mod tests {
         println!("{}", d.error_to_str());
     }
 
+    #[test]
+    fn config() {
+        let cfg = VP9DecoderConfig {
+            threads: 2,
+            ..VP9DecoderConfig::new()
+        };
+        let mut d = cfg.get_decoder::<()>().unwrap();
+        assert_eq!(d.config().threads, 2);
+
+        // Error concealment is VP8-only
+        let cfg = VP9DecoderConfig {
+            error_concealment: true,
+            ..VP9DecoderConfig::new()
+        };
+        assert_eq!(
+            d.reconfigure(&cfg),
+            Err(vpx_codec_err_t::VPX_CODEC_INCAPABLE)
+        );
+        assert_eq!(d.config().threads, 2);
+
+        let cfg = VP9DecoderConfig {
+            codec: VPXCodecId::VP8,
+            ..VP9DecoderConfig::new()
+        };
+        d.reconfigure(&cfg).unwrap();
+        assert_eq!(d.config().codec, VPXCodecId::VP8);
+    }
+
+    #[cfg(feature = "codec-trait")]
+    #[test]
+    fn config_codec_trait() {
+        use av_codec::decoder::Descriptor;
+
+        const DESCR: &Des = &Des::with_config(VP9DecoderConfig {
+            threads: 4,
+            ..VP9DecoderConfig::new()
+        });
+
+        let d = DESCR.create();
+        assert_eq!(d.config().threads, 4);
+
+        let mut cfg = VP9DecoderConfig::new();
+        cfg.set_option("w", 640u64.into()).unwrap();
+        cfg.set_option("h", 480u64.into()).unwrap();
+        assert_eq!((cfg.width, cfg.height), (640, 480));
+        assert!(cfg.set_option("threads", true.into()).is_err());
+        assert!(cfg.set_option("deinterlace", true.into()).is_err());
+    }
+
+    #[cfg(feature = "codec-trait")]
+    #[test]
+    fn config_context() {
+        use av_codec::common::CodecList;
+        use av_codec::decoder::{Codecs, Context};
+
+        let mut cfg = VP9DecoderConfig::new();
+        cfg.set_option("threads", 2u64.into()).unwrap();
+        cfg.set_option("postproc", true.into()).unwrap();
+        let descr: &'static Des = Box::leak(Box::new(Des::with_config(cfg)));
+
+        let decoders = Codecs::from_list(&[descr]);
+        let ctx = Context::by_name(&decoders, "vp9").unwrap();
+        assert_eq!(ctx.decoder().config().threads, 2);
+        assert!(ctx.decoder().config().postproc);
+    }
+
+    #[test]
+    fn postproc_config() {
+        let cfg = PostProcConfig {
+            deblock: true,
+            mfqe: true,
+            deblocking_level: 4,
+            ..Default::default()
+        };
+        let raw = cfg.to_raw();
+
+        assert_eq!(raw.post_proc_flag, 9);
+        assert_eq!((raw.deblocking_level, raw.noise_level), (4, 0));
+        assert_eq!(PostProcConfig::default().to_raw().post_proc_flag, 0);
+    }
+
+    #[test]
+    fn postproc() {
+        let postproc = PostProcConfig {
+            deblock: true,
+            demacroblock: true,
+            deblocking_level: 8,
+            ..Default::default()
+        };
+
+        let mut d = VP9Decoder::<()>::new().unwrap();
+        assert_eq!(d.set_postproc(&postproc), Err(VPX_CODEC_INCAPABLE));
+
+        let cfg = VP9DecoderConfig {
+            postproc_config: Some(postproc),
+            ..VP9DecoderConfig::new()
+        };
+        let mut d = match cfg.get_decoder::<()>() {
+            Ok(d) => d,
+            // libvpx built without VP9 post-processing
+            Err(VPX_CODEC_INCAPABLE) => return,
+            Err(err) => panic!("{:?}", err),
+        };
+
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        let mut out = 0;
+        for i in 0..4 {
+            enc::encode_pattern(&mut e, i);
+
+            if i == 2 {
+                let noise = PostProcConfig {
+                    add_noise: true,
+                    noise_level: 2,
+                    ..postproc
+                };
+                d.set_postproc(&noise).unwrap();
+                assert_eq!(d.config().postproc_config, Some(noise));
+            }
+
+            while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+                d.decode(&pkt.data, None).unwrap();
+                while d.get_frame().is_some() {
+                    out += 1;
+                }
+            }
+        }
+
+        assert!(out > 0);
+    }
+
+    #[test]
+    fn controls() {
+        let w = 320;
+        let h = 240;
+
+        let mut e = enc::setup_with_timebase(w, h, 1, 1000);
+
+        let mut d = VP9Decoder::<()>::new().unwrap();
+
+        d.set_skip_loop_filter(true).unwrap();
+        #[cfg(vpx_1_8)]
+        d.set_row_mt(true).unwrap();
+        d.set_byte_alignment(32).unwrap();
+        assert!(d.set_byte_alignment(33).is_err());
+
+        let mut packets = Vec::new();
+        enc::encode_pattern(&mut e, 0);
+        while let Some(p) = e.get_packet() {
+            packets.push(p);
+        }
+        e.flush().unwrap();
+        while let Some(p) = e.get_packet() {
+            packets.push(p);
+        }
+
+        for p in packets {
+            if let VPXPacket::Packet(pkt) = p {
+                d.decode(&pkt.data, None).unwrap();
+                while d.get_frame().is_some() {}
+            }
+        }
+
+        assert_eq!(d.frame_size().unwrap(), (w, h));
+        assert_eq!(d.display_size().unwrap(), (w, h));
+        assert_eq!(d.bit_depth().unwrap(), 8);
+        assert!(!d.frame_corrupted().unwrap());
+    }
+
+    #[test]
+    fn peek_invalid() {
+        assert!(peek_stream_info(VPXCodecId::VP9, &[]).is_err());
+        assert!(peek_stream_info(VPXCodecId::VP8, &[0; 4]).is_err());
+    }
+
     use super::super::encoder::tests as enc;
-    use super::super::encoder::VPXPacket;
+    use super::super::encoder::{VP9EncoderConfig, VPXPacket};
+    #[cfg(feature = "av-data")]
     use av_data::rational::*;
+    #[cfg(feature = "av-data")]
     use av_data::timeinfo::TimeInfo;
+    #[cfg(feature = "av-data")]
     #[test]
     fn decode() {
+        use av_data::params;
+        use av_data::pixel::formats::YUV420;
+
         let w = 800;
         let h = 600;
 
@@ -300,6 +1689,12 @@ mod tests {
                 let p = e.get_packet();
 
                 if let Some(VPXPacket::Packet(ref pkt)) = p {
+                    if pkt.is_key {
+                        let si = peek_stream_info(VPXCodecId::VP9, &pkt.data).unwrap();
+                        assert_eq!((si.width, si.height), (w, h));
+                        assert!(si.is_keyframe);
+                    }
+
                     d.decode(&pkt.data, None).unwrap();
 
                     // No multiframe expected.
@@ -316,6 +1711,419 @@ mod tests {
         if out != 1 {
             panic!("No frame decoded");
         }
+
+        let si = d.stream_info().unwrap();
+        assert_eq!((si.width, si.height), (w, h));
+
+        let params = d.get_params().unwrap();
+        if let Some(params::MediaKind::Video(info)) = params.kind {
+            assert_eq!(info.width, w as usize);
+            assert_eq!(info.height, h as usize);
+            assert_eq!(info.format.as_deref(), Some(YUV420));
+        } else {
+            panic!("No video information");
+        }
+    }
+
+    #[test]
+    fn decode_pooled() {
+        let w = 320;
+        let h = 240;
+
+        let mut e = enc::setup_with_timebase(w, h, 1, 1000);
+
+        let cfg = VP9DecoderConfig {
+            frame_buffer_pool: true,
+            ..VP9DecoderConfig::new()
+        };
+        let mut copied = VP9Decoder::<()>::new().unwrap();
+        let mut pooled = cfg.get_decoder::<()>().unwrap();
+        let mut frames = Vec::new();
+
+        for i in 0..10 {
+            enc::encode_pattern(&mut e, i);
+
+            while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+                copied.decode(&pkt.data, None).unwrap();
+                pooled.decode(&pkt.data, None).unwrap();
+
+                while let Some((a, _)) = copied.get_frame() {
+                    let (b, _) = pooled.get_frame().unwrap();
+                    frames.push((a, b));
+                }
+            }
+        }
+
+        assert!(!frames.is_empty());
+
+        // The frames must outlive the decoder.
+        drop(pooled);
+
+        for (a, mut b) in frames {
+            assert_eq!(a.format(), b.format());
+            assert_eq!((b.width(), b.height()), (w, h));
+
+            for plane in 0..a.plane_count() {
+                assert!(a.rows(plane).eq(b.rows(plane)));
+            }
+
+            b.plane_mut(0)[0] = 0;
+        }
+    }
+
+    #[test]
+    fn decode_ref() {
+        use self::vpx_img_fmt_t::*;
+
+        let w = 176;
+        let h = 144;
+
+        let mut e = enc::setup_with_timebase(w, h, 1, 1000);
+
+        let mut owned = VP9Decoder::<()>::new().unwrap();
+        let mut borrowed = VP9Decoder::<()>::new().unwrap();
+        let mut out = 0;
+
+        for i in 0..10 {
+            enc::encode_pattern(&mut e, i);
+
+            while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+                owned.decode(&pkt.data, None).unwrap();
+                borrowed.decode(&pkt.data, None).unwrap();
+
+                while let Some((frame, _)) = owned.get_frame() {
+                    let (img, _) = borrowed.get_frame_ref().unwrap();
+
+                    assert_eq!((img.width(), img.height()), (w, h));
+                    assert_eq!(img.bit_depth(), 8);
+                    assert!(!img.is_high_bitdepth());
+                    assert_eq!(img.format(), ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap());
+                    assert_eq!(img.plane_size(1), (w as usize / 2, h as usize / 2));
+
+                    let copy = img.to_image(32).unwrap();
+                    assert_eq!(copy.format(), img.format());
+
+                    for plane in 0..img.plane_count() {
+                        assert!(img.rows(plane).eq(frame.rows(plane)));
+                        assert!(copy.rows(plane).eq(frame.rows(plane)));
+                    }
+                    out += 1;
+                }
+            }
+        }
+
+        assert!(out > 0);
+        assert!(borrowed.get_frame_ref().is_none());
+    }
+
+    #[test]
+    fn decode_color() {
+        use self::vpx_color_range::*;
+        use self::vpx_color_space::*;
+
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        e.set_color_space(VPX_CS_BT_709).unwrap();
+        e.set_color_range(VPX_CR_FULL_RANGE).unwrap();
+
+        let record = e.codec_config_record(None);
+        assert_eq!(record.color_space(), Some(VPX_CS_BT_709));
+        assert!(record.full_range);
+
+        enc::encode_pattern(&mut e, 0);
+        e.flush().unwrap();
+
+        let mut d = VP9Decoder::<()>::new().unwrap();
+        while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+            d.decode(&pkt.data, None).unwrap();
+        }
+
+        let (frame, _) = d.get_frame().unwrap();
+        assert_eq!(frame.color_space(), VPX_CS_BT_709);
+        assert_eq!(frame.color_range(), VPX_CR_FULL_RANGE);
+    }
+
+    #[derive(Debug)]
+    struct Tracked(usize, Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn send_sync() {
+        fn is_send<T: Send>() {}
+        fn is_sync<T: Sync>() {}
+
+        is_send::<VP9Decoder<Box<u8>>>();
+        is_sync::<VP9Decoder<Box<u8>>>();
+        is_send::<DecodedFrame>();
+        is_sync::<DecodedFrame>();
+    }
+
+    #[test]
+    fn private_data() {
+        let mut p = PrivateData::new();
+        let a = p.insert("a");
+        let b = p.insert("b");
+        let c = p.insert("c");
+
+        // Superframes and show_existing_frame reuse the token
+        assert_eq!(p.take(b).as_deref(), Some(&"b"));
+        assert_eq!(p.take(b), None);
+        assert_eq!(p.take(a), None);
+        assert_eq!(p.pending.len(), 1);
+
+        p.remove(c);
+        assert!(p.pending.is_empty());
+    }
+
+    #[test]
+    fn decode_private() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        let mut packets = Vec::new();
+
+        for i in 0..10 {
+            enc::encode_pattern(&mut e, i);
+        }
+        e.flush().unwrap();
+        while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+            packets.push(pkt.data);
+        }
+
+        // Every picture gets the data of the packet that produced it
+        let mut d = VP9Decoder::new().unwrap();
+        let mut seen = Vec::new();
+        for (i, pkt) in packets.iter().enumerate() {
+            d.decode(pkt, Tracked(i, dropped.clone())).unwrap();
+            while let Some((_, private)) = d.get_frame() {
+                seen.push(private.unwrap().0);
+            }
+        }
+        d.flush().unwrap();
+        while let Some((_, private)) = d.get_frame() {
+            seen.push(private.unwrap().0);
+        }
+        drop(d);
+
+        assert!(!seen.is_empty());
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(dropped.load(Ordering::SeqCst), packets.len());
+
+        // Nothing leaks if the pictures are never retrieved
+        dropped.store(0, Ordering::SeqCst);
+        let mut d = VP9Decoder::new().unwrap();
+        for (i, pkt) in packets.iter().enumerate() {
+            d.decode(pkt, Tracked(i, dropped.clone())).unwrap();
+        }
+        drop(d);
+        assert_eq!(dropped.load(Ordering::SeqCst), packets.len());
+
+        // Nor if the decoder rejects the packet
+        dropped.store(0, Ordering::SeqCst);
+        let mut d = VP9Decoder::new().unwrap();
+        assert!(d.decode(&[0u8; 4], Tracked(0, dropped.clone())).is_err());
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn decode_all() {
+        use self::vpx_img_fmt_t::*;
+
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        let format = ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap();
+        let (luma, chroma) = (vec![16u8; 64 * 48], vec![128u8; 32 * 24]);
+        let img = crate::encoder::InputImage {
+            format,
+            planes: [&luma, &chroma, &chroma],
+            strides: [64, 32, 32],
+        };
+
+        let packets: Vec<_> = e
+            .encode_all((0..10).map(|pts| (img, pts)))
+            .unwrap()
+            .into_iter()
+            .filter_map(|p| match p {
+                VPXPacket::Packet(pkt) => Some(pkt),
+                _ => None,
+            })
+            .collect();
+        assert!(!packets.is_empty());
+        assert!(e.packets().next().is_none());
+
+        let mut d = VP9Decoder::new().unwrap();
+        let frames = d
+            .decode_all(packets.iter().map(|pkt| (&pkt.data, Some(pkt.pts))))
+            .unwrap();
+
+        assert_eq!(frames.len(), 10);
+        for (pts, (frame, private)) in frames.iter().enumerate() {
+            assert_eq!(private.as_deref(), Some(&(pts as i64)));
+            assert_eq!((frame.width(), frame.height()), (64, 48));
+        }
+        assert!(d.frames().next().is_none());
+    }
+
+    #[test]
+    fn decode_info() {
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        let mut d = VP9Decoder::<()>::new().unwrap();
+        let mut infos = Vec::new();
+
+        for i in 0..5 {
+            enc::encode_pattern(&mut e, i);
+        }
+        e.flush().unwrap();
+
+        while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+            d.decode(&pkt.data, None).unwrap();
+            while let Some((frame, _)) = d.get_frame() {
+                infos.push(frame.info());
+            }
+        }
+
+        assert_eq!(infos.len(), 5);
+        // A keyframe refreshes all the reference buffers
+        assert_eq!(infos[0].ref_updates, 0xff);
+        assert!(infos.iter().all(|info| !info.corrupted && !info.concealed));
+    }
+
+    #[test]
+    fn decode_render_size() {
+        let mut c = VP9EncoderConfig::new().unwrap();
+        c.cfg.g_w = 64;
+        c.cfg.g_h = 48;
+        c.cfg.g_timebase.num = 1;
+        c.cfg.g_timebase.den = 1000;
+        c.render_size = Some((128, 48));
+        let mut e = c.get_encoder().unwrap();
+
+        assert_eq!(e.set_render_size(0, 48), Err(VPX_CODEC_INVALID_PARAM));
+
+        enc::encode_pattern(&mut e, 0);
+        e.flush().unwrap();
+
+        let mut d = VP9Decoder::<()>::new().unwrap();
+        while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+            d.decode(&pkt.data, None).unwrap();
+        }
+
+        assert_eq!(d.display_size(), Ok((128, 48)));
+
+        let (img, _) = d.get_frame_ref().unwrap();
+        assert_eq!((img.width(), img.height()), (64, 48));
+        assert_eq!(img.render_size(), (128, 48));
+        assert_eq!(img.to_frame().render_size(), (128, 48));
+    }
+
+    #[cfg(feature = "av-data")]
+    #[test]
+    fn decode_color_formaton() {
+        use av_data::frame::{Frame, FrameType, MediaKind, VideoInfo};
+        use av_data::pixel::{formats::YUV420, ColorPrimaries, MatrixCoefficients};
+
+        let (w, h) = (64, 48);
+        let t = TimeInfo {
+            pts: Some(0),
+            timebase: Some(Rational64::new(1, 1000)),
+            ..Default::default()
+        };
+        let mut e = enc::setup(w, h, &t);
+
+        let mut format = *YUV420;
+        format.primaries = ColorPrimaries::BT709;
+        format.matrix = MatrixCoefficients::BT709;
+        let v = VideoInfo::new(
+            w as usize,
+            h as usize,
+            false,
+            FrameType::OTHER,
+            Arc::new(format),
+        );
+        e.encode(&Frame::new_default_frame(v, Some(t.clone())))
+            .unwrap();
+        e.flush().unwrap();
+
+        let mut d = VP9Decoder::<()>::new().unwrap();
+        while let Some(VPXPacket::Packet(pkt)) = e.get_packet() {
+            d.decode(&pkt.data, None).unwrap();
+        }
+
+        let (frame, _) = d.get_frame().unwrap();
+        let frame = Frame::from(frame);
+        match frame.kind {
+            MediaKind::Video(ref info) => {
+                assert_eq!(info.format.primaries, ColorPrimaries::BT709);
+                assert_eq!(info.format.matrix, MatrixCoefficients::BT709);
+                assert_eq!(
+                    ImageFormat::from_formaton(&info.format),
+                    ImageFormat::from_formaton(YUV420)
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        // Explicit controls win over the input format
+        let mut e = enc::setup(w, h, &t);
+        e.set_color_space(vpx_color_space::VPX_CS_BT_601).unwrap();
+        let v = VideoInfo::new(
+            w as usize,
+            h as usize,
+            false,
+            FrameType::OTHER,
+            Arc::new(format),
+        );
+        e.encode(&Frame::new_default_frame(v, Some(t.clone())))
+            .unwrap();
+        assert_eq!(
+            e.codec_config_record(None).color_space(),
+            Some(vpx_color_space::VPX_CS_BT_601)
+        );
+    }
+
+    #[cfg(feature = "codec-trait")]
+    #[test]
+    fn timing() {
+        let t = |pts| TimeInfo {
+            pts: Some(pts),
+            duration: Some(2),
+            timebase: Some(Rational64::new(1, 30)),
+            ..Default::default()
+        };
+        let mut timing = decoder_trait::Timing::default();
+
+        for pts in &[0, 2, 4, 6, 8] {
+            timing.sent(&t(*pts));
+        }
+
+        assert_eq!(timing.received(Some(t(0))).pts, Some(0));
+        // Lost private data, the earliest pending packet is used
+        assert_eq!(timing.received(None).pts, Some(2));
+        // 4 was a hidden frame
+        assert_eq!(timing.received(Some(t(6))).pts, Some(6));
+        assert_eq!(timing.received(None).pts, Some(8));
+
+        // Nothing pending, extrapolate from the last frame
+        let next = timing.received(Some(TimeInfo::default()));
+        assert_eq!(next.pts, Some(10));
+        assert_eq!(next.duration, Some(2));
+        assert_eq!(next.timebase, Some(Rational64::new(1, 30)));
+        assert_eq!(timing.received(None).pts, Some(12));
+    }
+
+    #[cfg(feature = "codec-trait")]
+    #[test]
+    fn descriptors() {
+        use av_codec::decoder::Descriptor;
+
+        assert_eq!(VP8_DESCR.describe().codec, "vp8");
+        assert_eq!(VP8_DESCR.describe().mime, "video/VP8");
+        assert_eq!(VP9_DESCR.describe().codec, "vp9");
+        assert_eq!(VP9_DESCR.describe().mime, "video/VP9");
     }
 
     #[cfg(all(test, feature = "codec-trait"))]
@@ -351,6 +2159,8 @@ mod tests {
         enc.configure().unwrap();
         dec.configure().unwrap();
 
+        dec.set_extradata(&enc.get_extradata().unwrap());
+
         let mut f = Arc::new(enc::setup_frame(w, h, &t));
         let mut enc_out = 0;
         let mut dec_out = 0;
@@ -371,6 +2181,7 @@ mod tests {
                             match dec.receive_frame() {
                                 Ok(f) => {
                                     println!("{:#?}", f);
+                                    assert!(f.t.pts.is_some());
                                     dec_out = 1;
                                 }
                                 Err(e) => match e {
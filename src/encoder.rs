@@ -2,17 +2,21 @@
 //!
 //!
 
-use crate::common::VPXCodec;
+#[cfg(feature = "av-data")]
+use crate::common::formaton_color;
+use crate::common::{ImageFormat, VPXCodec};
 use crate::ffi::*;
+use crate::image::Image;
+use crate::vpcc::*;
 
+use std::collections::HashMap;
 use std::mem::MaybeUninit;
 use std::os::raw::c_ulong;
 use std::ptr;
+use std::sync::Arc;
 
-use av_data::frame::{Frame, FrameBufferConv, MediaKind};
-use av_data::packet::Packet;
-use av_data::pixel::formats::YUV420;
-use av_data::pixel::Formaton;
+#[cfg(feature = "av-data")]
+use av_data::{frame::Frame, pixel::Formaton};
 
 use self::vpx_codec_err_t::*;
 
@@ -23,10 +27,54 @@ pub struct PSNR {
     pub psnr: [f64; 4],
 }
 
+/// Compressed frame
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncodedPacket {
+    pub data: Vec<u8>,
+    /// Presentation timestamp, in timebase units
+    pub pts: i64,
+    /// Duration, in timebase units
+    pub duration: u64,
+    pub is_key: bool,
+    /// The frame is not meant to be displayed, e.g. an alternate reference
+    pub is_invisible: bool,
+}
+
+#[cfg(feature = "av-data")]
+impl From<EncodedPacket> for av_data::packet::Packet {
+    fn from(pkt: EncodedPacket) -> Self {
+        let mut p = av_data::packet::Packet::new();
+
+        p.data = pkt.data;
+        p.t.pts = Some(pkt.pts);
+        p.t.duration = Some(pkt.duration);
+        p.is_key = pkt.is_key;
+
+        p
+    }
+}
+
+#[cfg(feature = "av-data")]
+impl From<&av_data::packet::Packet> for EncodedPacket {
+    /// Copy the data, the missing timestamps default to `0`
+    ///
+    /// `av_data` packets cannot flag invisible frames, `is_invisible`
+    /// is always `false`.
+    fn from(pkt: &av_data::packet::Packet) -> Self {
+        EncodedPacket {
+            data: pkt.data.clone(),
+            pts: pkt.t.pts.unwrap_or(0),
+            duration: pkt.t.duration.unwrap_or(0),
+            is_key: pkt.is_key,
+            is_invisible: false,
+        }
+    }
+}
+
 /// Safe wrapper around `vpx_codec_cx_pkt`
 #[derive(Clone, Debug)]
 pub enum VPXPacket {
-    Packet(Packet),
+    Packet(EncodedPacket),
     Stats(Vec<u8>),
     MBStats(Vec<u8>),
     PSNR(PSNR),
@@ -48,19 +96,15 @@ impl VPXPacket {
         match pkt.kind {
             VPX_CODEC_CX_FRAME_PKT => {
                 let f = unsafe { pkt.data.frame };
-                let mut p = Packet::with_capacity(f.sz as usize);
-                unsafe {
-                    ptr::copy_nonoverlapping(
-                        f.buf as *const u8,
-                        p.data.as_mut_ptr(),
-                        f.sz as usize,
-                    );
-                    p.data.set_len(f.sz as usize);
-                }
-                p.t.pts = Some(f.pts);
-                p.is_key = (f.flags & VPX_FRAME_IS_KEY) != 0;
-
-                VPXPacket::Packet(p)
+                let data = unsafe { std::slice::from_raw_parts(f.buf as *const u8, f.sz) };
+
+                VPXPacket::Packet(EncodedPacket {
+                    data: data.to_vec(),
+                    pts: f.pts,
+                    duration: f.duration as u64,
+                    is_key: (f.flags & VPX_FRAME_IS_KEY) != 0,
+                    is_invisible: (f.flags & VPX_FRAME_IS_INVISIBLE) != 0,
+                })
             }
             VPX_CODEC_STATS_PKT => {
                 let b = to_buffer(unsafe { pkt.data.twopass_stats });
@@ -89,42 +133,50 @@ impl VPXPacket {
 
 pub struct VP9EncoderConfig {
     pub cfg: vpx_codec_enc_cfg,
+    /// Size the pictures are meant to be displayed at, if it differs from
+    /// the coded size
+    pub render_size: Option<(u32, u32)>,
+    /// Statistics collected by the first pass, the data of the
+    /// `VPXPacket::Stats` packets, for the last pass of a two-pass encode
+    ///
+    /// It replaces `cfg.rc_twopass_stats_in`.
+    pub twopass_stats: Option<Arc<[u8]>>,
+    /// Per-macroblock statistics collected by the first pass, the data of
+    /// the `VPXPacket::MBStats` packets
+    ///
+    /// It replaces `cfg.rc_firstpass_mb_stats_in`.
+    pub firstpass_mb_stats: Option<Arc<[u8]>>,
 }
 
-unsafe impl Send for VP9EncoderConfig {} // TODO: Make sure it cannot be abused
-
-// TODO: Extend
-fn map_formaton(img: &mut vpx_image, fmt: &Formaton) {
-    use self::vpx_img_fmt_t::*;
-    if fmt == YUV420 {
-        img.fmt = VPX_IMG_FMT_I420;
-    } else {
-        unimplemented!();
+// The only pointers in `cfg` are the two statistics buffers, they are never
+// read: the encoder points them to the owned `twopass_stats` and
+// `firstpass_mb_stats` instead.
+unsafe impl Send for VP9EncoderConfig {}
+unsafe impl Sync for VP9EncoderConfig {}
+
+fn fixed_buf(data: &Option<Arc<[u8]>>) -> vpx_fixed_buf_t {
+    match data {
+        Some(data) => vpx_fixed_buf_t {
+            buf: data.as_ptr() as *mut _,
+            sz: data.len() as _,
+        },
+        None => vpx_fixed_buf_t {
+            buf: ptr::null_mut(),
+            sz: 0,
+        },
     }
-    img.bit_depth = 8;
-    img.bps = 12;
-    img.x_chroma_shift = 1;
-    img.y_chroma_shift = 1;
 }
 
-fn img_from_frame(frame: &Frame) -> vpx_image {
-    // This is sound because `vpx_image` is a repr(C) struct containing fields that can be
-    // zeroed without causing UB
-    let mut img = unsafe { MaybeUninit::zeroed().assume_init() };
-
-    if let MediaKind::Video(ref v) = frame.kind {
-        map_formaton(&mut img, &v.format);
-        img.d_w = v.width as u32;
-        img.d_h = v.height as u32;
-    }
-    // populate the buffers
-    for i in 0..frame.buf.count() {
-        let s: &[u8] = frame.buf.as_slice(i).unwrap();
-        img.planes[i] = s.as_ptr() as *mut u8;
-        img.stride[i] = frame.buf.linesize(i).unwrap() as i32;
-    }
-
-    img
+/// Uncompressed picture borrowed from the caller
+///
+/// Its size must match the one the encoder is configured with.
+#[derive(Clone, Copy, Debug)]
+pub struct InputImage<'a> {
+    pub format: ImageFormat,
+    /// Planes in Y, U, V order
+    pub planes: [&'a [u8]; 3],
+    /// Distance in bytes between two rows of each plane
+    pub strides: [usize; 3],
 }
 
 // TODO: provide a builder?
@@ -139,7 +191,12 @@ impl VP9EncoderConfig {
         match ret {
             VPX_CODEC_OK => {
                 let cfg = unsafe { cfg.assume_init() };
-                Ok(VP9EncoderConfig { cfg })
+                Ok(VP9EncoderConfig {
+                    cfg,
+                    render_size: None,
+                    twopass_stats: None,
+                    firstpass_mb_stats: None,
+                })
             }
             _ => Err(ret),
         }
@@ -149,28 +206,213 @@ impl VP9EncoderConfig {
     pub fn get_encoder(&mut self) -> Result<VP9Encoder, vpx_codec_err_t> {
         VP9Encoder::new(self)
     }
+
+    /// Describe the stream the configuration would produce
+    ///
+    /// `format` is the layout of the images that will be encoded, if
+    /// known, and provides the chroma subsampling.
+    pub fn codec_config_record(&self, format: Option<ImageFormat>) -> VPCodecConfigurationRecord {
+        config_record(
+            &self.cfg,
+            &ControlState::default(),
+            FormatHints::from_image_format(format),
+        )
+    }
+
+    /// Describe the stream the configuration would produce
+    ///
+    /// `format` is the pixel format of the frames that will be encoded
+    /// and provides the chroma subsampling and color information.
+    #[cfg(feature = "av-data")]
+    pub fn codec_config_record_for(&self, format: &Formaton) -> VPCodecConfigurationRecord {
+        config_record(
+            &self.cfg,
+            &ControlState::default(),
+            FormatHints::from_formaton(format),
+        )
+    }
+}
+
+/// Encoder controls that are reflected in the codec configuration record
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ControlState {
+    color_space: Option<vpx_color_space_t>,
+    color_range: Option<vpx_color_range_t>,
+    target_level: Option<u8>,
+    // The color controls were derived from the input rather than set
+    // by the caller, a different input may replace them.
+    color_from_input: bool,
+}
+
+impl ControlState {
+    fn update(&mut self, id: vp8e_enc_control_id, val: i32) {
+        use self::vp8e_enc_control_id::*;
+        use self::vpx_color_range::*;
+        use self::vpx_color_space::*;
+
+        match id {
+            VP9E_SET_COLOR_SPACE => {
+                self.color_space = match val {
+                    0 => Some(VPX_CS_UNKNOWN),
+                    1 => Some(VPX_CS_BT_601),
+                    2 => Some(VPX_CS_BT_709),
+                    3 => Some(VPX_CS_SMPTE_170),
+                    4 => Some(VPX_CS_SMPTE_240),
+                    5 => Some(VPX_CS_BT_2020),
+                    7 => Some(VPX_CS_SRGB),
+                    _ => None,
+                };
+                self.color_from_input = false;
+            }
+            VP9E_SET_COLOR_RANGE => {
+                self.color_range = match val {
+                    0 => Some(VPX_CR_STUDIO_RANGE),
+                    1 => Some(VPX_CR_FULL_RANGE),
+                    _ => None,
+                };
+                self.color_from_input = false;
+            }
+            VP9E_SET_TARGET_LEVEL => {
+                self.target_level = if (10..=62).contains(&val) {
+                    Some(val as u8)
+                } else {
+                    None
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Tell whether the color information of the input may be signalled
+    fn accepts_input_color(&self) -> bool {
+        self.color_from_input || (self.color_space.is_none() && self.color_range.is_none())
+    }
+}
+
+/// What the input format tells about the stream
+#[derive(Clone, Copy, Debug, Default)]
+struct FormatHints {
+    chroma_subsampling: Option<ChromaSubsampling>,
+    colour: Option<(u8, u8, u8)>,
+    full_range: Option<bool>,
+}
+
+impl FormatHints {
+    fn from_image_format(format: Option<ImageFormat>) -> FormatHints {
+        FormatHints {
+            chroma_subsampling: format.and_then(|f| {
+                let (xs, ys) = f.chroma_shift();
+                ChromaSubsampling::from_shift(xs, ys)
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "av-data")]
+    fn from_formaton(format: &Formaton) -> FormatHints {
+        FormatHints {
+            chroma_subsampling: ChromaSubsampling::from_formaton(format),
+            colour: Some((
+                format.primaries as u8,
+                format.xfer as u8,
+                format.matrix as u8,
+            )),
+            full_range: formaton_full_range(format),
+        }
+    }
+}
+
+fn config_record(
+    cfg: &vpx_codec_enc_cfg,
+    controls: &ControlState,
+    hints: FormatHints,
+) -> VPCodecConfigurationRecord {
+    let bit_depth = cfg.g_bit_depth as u8;
+    let chroma_subsampling = hints
+        .chroma_subsampling
+        .unwrap_or(if cfg.g_profile & 1 == 0 {
+            ChromaSubsampling::Yuv420Colocated
+        } else {
+            ChromaSubsampling::Yuv444
+        });
+
+    // The encoder controls are what ends up in the bitstream, the
+    // format provides the information otherwise.
+    let (colour_primaries, transfer_characteristics, matrix_coefficients) =
+        match (controls.color_space, hints.colour) {
+            (Some(cs), _) => color_space_to_iso(cs, bit_depth),
+            (None, Some(colour)) => colour,
+            (None, None) => (UNSPECIFIED, UNSPECIFIED, UNSPECIFIED),
+        };
+
+    let full_range = match controls.color_range {
+        Some(range) => range == vpx_color_range::VPX_CR_FULL_RANGE,
+        None => hints.full_range.unwrap_or(false),
+    };
+
+    VPCodecConfigurationRecord {
+        profile: cfg.g_profile as u8,
+        level: controls
+            .target_level
+            .unwrap_or_else(|| level_for_size(cfg.g_w, cfg.g_h)),
+        bit_depth,
+        chroma_subsampling,
+        full_range,
+        colour_primaries,
+        transfer_characteristics,
+        matrix_coefficients,
+    }
 }
 
 /// VP9 Encoder
+///
+/// It can be moved to another thread but not shared, the methods that
+/// use the libvpx context need `&mut self`.
+///
+/// ```compile_fail
+/// fn is_sync<T: Sync>() {}
+/// is_sync::<libvpx::encoder::VP9Encoder>();
+/// ```
 pub struct VP9Encoder {
     pub(crate) ctx: vpx_codec_ctx_t,
     pub(crate) iter: vpx_codec_iter_t,
+    pub(crate) cfg: vpx_codec_enc_cfg,
+    pub(crate) controls: ControlState,
+    // Last input format and its layout
+    #[cfg(feature = "av-data")]
+    formaton: Option<(Arc<Formaton>, ImageFormat)>,
+    // The image describing each input format seen so far
+    images: HashMap<ImageFormat, vpx_image>,
+    deadline: c_ulong,
+    // Referenced by `cfg`, libvpx reads them while encoding.
+    _stats: [Option<Arc<[u8]>>; 2],
 }
 
-unsafe impl Send for VP9Encoder {} // TODO: Make sure it cannot be abused
+// libvpx contexts are not bound to the thread that created them. The cached
+// `images` never keep the plane pointers of a previous input and the
+// statistics buffers are owned by the encoder.
+unsafe impl Send for VP9Encoder {}
 
 impl VP9Encoder {
     /// Create a new encoder using the provided configuration
     ///
     /// You may use `get_encoder` instead.
     pub fn new(cfg: &mut VP9EncoderConfig) -> Result<VP9Encoder, vpx_codec_err_t> {
+        let mut raw = cfg.cfg;
+        raw.rc_twopass_stats_in = fixed_buf(&cfg.twopass_stats);
+        raw.rc_firstpass_mb_stats_in = fixed_buf(&cfg.firstpass_mb_stats);
+
         let mut ctx = MaybeUninit::uninit();
+        let flags = match raw.g_bit_depth {
+            vpx_bit_depth::VPX_BITS_8 => 0,
+            _ => VPX_CODEC_USE_HIGHBITDEPTH as vpx_codec_flags_t,
+        };
         let ret = unsafe {
             vpx_codec_enc_init_ver(
                 ctx.as_mut_ptr(),
                 vpx_codec_vp9_cx(),
-                &cfg.cfg,
-                0,
+                &raw,
+                flags,
                 VPX_ENCODER_ABI_VERSION as i32,
             )
         };
@@ -178,10 +420,23 @@ impl VP9Encoder {
         match ret {
             VPX_CODEC_OK => {
                 let ctx = unsafe { ctx.assume_init() };
-                Ok(VP9Encoder {
+                let mut enc = VP9Encoder {
                     ctx,
                     iter: ptr::null(),
-                })
+                    cfg: raw,
+                    controls: ControlState::default(),
+                    #[cfg(feature = "av-data")]
+                    formaton: None,
+                    images: HashMap::new(),
+                    deadline: c_ulong::from(VPX_DL_GOOD_QUALITY),
+                    _stats: [cfg.twopass_stats.clone(), cfg.firstpass_mb_stats.clone()],
+                };
+
+                if let Some((w, h)) = cfg.render_size {
+                    enc.set_render_size(w, h)?;
+                }
+
+                Ok(enc)
             }
             _ => Err(ret),
         }
@@ -194,58 +449,167 @@ impl VP9Encoder {
         let ret = unsafe { vpx_codec_control_(&mut self.ctx, id as i32, val) };
 
         match ret {
-            VPX_CODEC_OK => Ok(()),
+            VPX_CODEC_OK => {
+                self.controls.update(id, val);
+                Ok(())
+            }
             _ => Err(ret),
         }
     }
 
-    // TODO: Cache the image information
-    //
-    /// Send an uncompressed frame to the encoder
+    /// Signal the size the pictures are meant to be displayed at
     ///
-    /// Call [`get_packet`] to receive the compressed data.
+    /// It fails with `VPX_CODEC_INVALID_PARAM` if either dimension is 0 or
+    /// larger than 65536.
     ///
-    /// It calls `vpx_codec_encode`.
-    ///
-    /// [`get_packet`]: #method.get_packet
-    pub fn encode(&mut self, frame: &Frame) -> Result<(), vpx_codec_err_t> {
-        let img = img_from_frame(frame);
+    /// It calls `vpx_codec_control_` with `VP9E_SET_RENDER_SIZE`.
+    pub fn set_render_size(&mut self, w: u32, h: u32) -> Result<(), vpx_codec_err_t> {
+        if !(1..=65536).contains(&w) || !(1..=65536).contains(&h) {
+            return Err(VPX_CODEC_INVALID_PARAM);
+        }
 
+        let size = [w as i32, h as i32];
         let ret = unsafe {
-            vpx_codec_encode(
+            vpx_codec_control_(
                 &mut self.ctx,
-                &img,
-                frame.t.pts.unwrap(),
-                1,
-                0,
-                c_ulong::from(VPX_DL_GOOD_QUALITY),
+                vp8e_enc_control_id::VP9E_SET_RENDER_SIZE as i32,
+                size.as_ptr(),
             )
         };
 
-        self.iter = ptr::null();
-
         match ret {
             VPX_CODEC_OK => Ok(()),
             _ => Err(ret),
         }
     }
 
-    /// Notify the encoder that no more data will be sent
+    /// Set the time the encoder may spend on each picture
     ///
-    /// Call [`get_packet`] to receive the compressed data.
+    /// `deadline` is in microseconds, `VPX_DL_REALTIME` and
+    /// `VPX_DL_BEST_QUALITY` select the fastest and the slowest mode.
+    /// The default is `VPX_DL_GOOD_QUALITY`.
+    pub fn set_deadline(&mut self, deadline: u64) {
+        self.deadline = deadline as c_ulong;
+    }
+
+    /// Signal the color space in the bitstream
     ///
-    /// It calls `vpx_codec_encode` with NULL arguments.
+    /// It takes precedence over the color information of the input.
     ///
-    /// [`get_packet`]: #method.get_packet
-    pub fn flush(&mut self) -> Result<(), vpx_codec_err_t> {
+    /// It calls `vpx_codec_control_` with `VP9E_SET_COLOR_SPACE`.
+    pub fn set_color_space(&mut self, cs: vpx_color_space_t) -> Result<(), vpx_codec_err_t> {
+        self.control(vp8e_enc_control_id::VP9E_SET_COLOR_SPACE, cs as i32)
+    }
+
+    /// Signal the color range in the bitstream
+    ///
+    /// It takes precedence over the color information of the input.
+    ///
+    /// It calls `vpx_codec_control_` with `VP9E_SET_COLOR_RANGE`.
+    pub fn set_color_range(&mut self, range: vpx_color_range_t) -> Result<(), vpx_codec_err_t> {
+        self.control(vp8e_enc_control_id::VP9E_SET_COLOR_RANGE, range as i32)
+    }
+
+    // libvpx ignores the color information of the input images, forward it
+    // to the bitstream unless the caller chose what to signal.
+    fn apply_input_color(
+        &mut self,
+        cs: Option<vpx_color_space_t>,
+        range: Option<vpx_color_range_t>,
+    ) -> Result<(), vpx_codec_err_t> {
+        if !self.controls.accepts_input_color() {
+            return Ok(());
+        }
+
+        if let Some(cs) = cs.filter(|&cs| self.controls.color_space != Some(cs)) {
+            self.set_color_space(cs)?;
+        }
+        if let Some(range) = range.filter(|&range| self.controls.color_range != Some(range)) {
+            self.set_color_range(range)?;
+        }
+        self.controls.color_from_input = true;
+
+        Ok(())
+    }
+
+    /// Describe the stream being produced
+    ///
+    /// It takes in account the configuration the encoder was created with
+    /// and the color space, color range and target level controls.
+    ///
+    /// `format` is the layout of the images being encoded, if known.
+    pub fn codec_config_record(&self, format: Option<ImageFormat>) -> VPCodecConfigurationRecord {
+        config_record(
+            &self.cfg,
+            &self.controls,
+            FormatHints::from_image_format(format),
+        )
+    }
+
+    /// Describe the stream being produced
+    ///
+    /// Same as [`codec_config_record`], `format` also provides the color
+    /// information if the controls do not set it.
+    ///
+    /// [`codec_config_record`]: #method.codec_config_record
+    #[cfg(feature = "av-data")]
+    pub fn codec_config_record_for(&self, format: &Formaton) -> VPCodecConfigurationRecord {
+        config_record(
+            &self.cfg,
+            &self.controls,
+            FormatHints::from_formaton(format),
+        )
+    }
+
+    #[cfg(feature = "av-data")]
+    fn image_format(&mut self, fmt: &Arc<Formaton>) -> Result<ImageFormat, vpx_codec_err_t> {
+        if let Some((ref cached, format)) = self.formaton {
+            if Arc::ptr_eq(cached, fmt) || **cached == **fmt {
+                return Ok(format);
+            }
+        }
+
+        let format = ImageFormat::from_formaton(fmt).ok_or(VPX_CODEC_INVALID_PARAM)?;
+        let (cs, range) = formaton_color(fmt);
+        self.apply_input_color(cs, range)?;
+        self.formaton = Some((fmt.clone(), format));
+
+        Ok(format)
+    }
+
+    fn prepare_image(&mut self, img: &InputImage<'_>) -> Result<vpx_image, vpx_codec_err_t> {
+        let (w, h) = (self.cfg.g_w, self.cfg.g_h);
+
+        img.format.check_planes(w, h, &img.planes, &img.strides)?;
+
+        let mut raw = *self
+            .images
+            .entry(img.format)
+            .or_insert_with(|| img.format.image(w, h));
+
+        for (i, (plane, stride)) in img.planes.iter().zip(img.strides.iter()).enumerate() {
+            raw.planes[i] = plane.as_ptr() as *mut u8;
+            raw.stride[i] = *stride as i32;
+        }
+
+        Ok(raw)
+    }
+
+    fn encode_raw(
+        &mut self,
+        img: *const vpx_image,
+        pts: i64,
+        duration: u64,
+        flags: vpx_enc_frame_flags_t,
+    ) -> Result<(), vpx_codec_err_t> {
         let ret = unsafe {
             vpx_codec_encode(
                 &mut self.ctx,
-                ptr::null_mut(),
-                0,
-                1,
-                0,
-                c_ulong::from(VPX_DL_GOOD_QUALITY),
+                img,
+                pts,
+                duration as c_ulong,
+                flags,
+                self.deadline,
             )
         };
 
@@ -257,6 +621,185 @@ impl VP9Encoder {
         }
     }
 
+    /// Send an uncompressed frame to the encoder
+    ///
+    /// Call [`get_packet`] to receive the compressed data.
+    ///
+    /// It fails with `VPX_CODEC_INVALID_PARAM` if the frame size does not
+    /// match the configuration, if its pixel format is not supported or
+    /// if its planes are too small.
+    ///
+    /// The color space and range of the pixel format are signalled in the
+    /// bitstream, unless they are set with [`set_color_space`] and
+    /// [`set_color_range`].
+    ///
+    /// It calls `vpx_codec_encode`.
+    ///
+    /// [`get_packet`]: #method.get_packet
+    /// [`set_color_space`]: #method.set_color_space
+    /// [`set_color_range`]: #method.set_color_range
+    #[cfg(feature = "av-data")]
+    pub fn encode(&mut self, frame: &Frame) -> Result<(), vpx_codec_err_t> {
+        use av_data::frame::{FrameBufferConv, MediaKind};
+
+        let format = match frame.kind {
+            MediaKind::Video(ref v)
+                if v.width == self.cfg.g_w as usize && v.height == self.cfg.g_h as usize =>
+            {
+                self.image_format(&v.format)?
+            }
+            _ => return Err(VPX_CODEC_INVALID_PARAM),
+        };
+
+        let mut planes: [&[u8]; 3] = [&[]; 3];
+        let mut strides = [0; 3];
+        for i in 0..3 {
+            planes[i] = frame.buf.as_slice(i).map_err(|_| VPX_CODEC_INVALID_PARAM)?;
+            strides[i] = frame.buf.linesize(i).map_err(|_| VPX_CODEC_INVALID_PARAM)?;
+        }
+
+        let img = InputImage {
+            format,
+            planes,
+            strides,
+        };
+
+        self.encode_image(&img, frame.t.pts.unwrap(), 1, 0)
+    }
+
+    /// Send an uncompressed picture to the encoder
+    ///
+    /// Call [`get_packet`] to receive the compressed data.
+    ///
+    /// It fails with `VPX_CODEC_INVALID_PARAM` if the planes cannot hold
+    /// a picture of the configured size.
+    ///
+    /// It calls `vpx_codec_encode`.
+    ///
+    /// [`get_packet`]: #method.get_packet
+    pub fn encode_image(
+        &mut self,
+        img: &InputImage<'_>,
+        pts: i64,
+        duration: u64,
+        flags: vpx_enc_frame_flags_t,
+    ) -> Result<(), vpx_codec_err_t> {
+        let raw = self.prepare_image(img)?;
+
+        self.encode_raw(&raw, pts, duration, flags)
+    }
+
+    /// Send an owned uncompressed picture to the encoder
+    ///
+    /// Call [`get_packet`] to receive the compressed data.
+    ///
+    /// It fails with `VPX_CODEC_INVALID_PARAM` if the displayed size of the
+    /// image does not match the configuration.
+    ///
+    /// A known color space of the image is signalled in the bitstream, as
+    /// for [`encode`].
+    ///
+    /// It calls `vpx_codec_encode`.
+    ///
+    /// [`get_packet`]: #method.get_packet
+    /// [`encode`]: #method.encode
+    pub fn encode_vpx_image(
+        &mut self,
+        img: &Image,
+        pts: i64,
+        duration: u64,
+        flags: vpx_enc_frame_flags_t,
+    ) -> Result<(), vpx_codec_err_t> {
+        if (img.width(), img.height()) != (self.cfg.g_w, self.cfg.g_h) {
+            return Err(VPX_CODEC_INVALID_PARAM);
+        }
+
+        if img.color_space() != vpx_color_space::VPX_CS_UNKNOWN {
+            self.apply_input_color(Some(img.color_space()), Some(img.color_range()))?;
+        }
+
+        self.encode_raw(img.as_ptr(), pts, duration, flags)
+    }
+
+    /// Send an uncompressed picture stored in separate planes
+    ///
+    /// `strides` are in bytes and `format` must use 8 bits per sample.
+    /// The planes must hold a picture of the configured size.
+    ///
+    /// It calls `vpx_codec_encode`.
+    pub fn encode_planes(
+        &mut self,
+        planes: &[&[u8]; 3],
+        strides: [usize; 3],
+        format: ImageFormat,
+        pts: i64,
+        duration: u64,
+        flags: vpx_enc_frame_flags_t,
+    ) -> Result<(), vpx_codec_err_t> {
+        if format.is_high_bitdepth() {
+            return Err(VPX_CODEC_INVALID_PARAM);
+        }
+
+        let img = InputImage {
+            format,
+            planes: *planes,
+            strides,
+        };
+
+        self.encode_image(&img, pts, duration, flags)
+    }
+
+    /// Send an uncompressed high bit depth picture stored in separate planes
+    ///
+    /// `strides` are in samples and `format` must use 16 bits per sample.
+    /// The encoder must be configured with a matching `g_bit_depth`.
+    ///
+    /// It calls `vpx_codec_encode`.
+    pub fn encode_planes_u16(
+        &mut self,
+        planes: &[&[u16]; 3],
+        strides: [usize; 3],
+        format: ImageFormat,
+        pts: i64,
+        duration: u64,
+        flags: vpx_enc_frame_flags_t,
+    ) -> Result<(), vpx_codec_err_t> {
+        if !format.is_high_bitdepth() {
+            return Err(VPX_CODEC_INVALID_PARAM);
+        }
+
+        let as_bytes = |p: &&[u16]| unsafe {
+            std::slice::from_raw_parts(p.as_ptr() as *const u8, p.len() * 2)
+        };
+        let mut byte_strides = [0; 3];
+        for (b, s) in byte_strides.iter_mut().zip(strides.iter()) {
+            *b = s.checked_mul(2).ok_or(VPX_CODEC_INVALID_PARAM)?;
+        }
+
+        let img = InputImage {
+            format,
+            planes: [
+                as_bytes(&planes[0]),
+                as_bytes(&planes[1]),
+                as_bytes(&planes[2]),
+            ],
+            strides: byte_strides,
+        };
+
+        self.encode_image(&img, pts, duration, flags)
+    }
+
+    /// Notify the encoder that no more data will be sent
+    ///
+    /// Call [`get_packet`] to receive the compressed data.
+    ///
+    /// It calls `vpx_codec_encode` with NULL arguments.
+    ///
+    /// [`get_packet`]: #method.get_packet
+    pub fn flush(&mut self) -> Result<(), vpx_codec_err_t> {
+        self.encode_raw(ptr::null(), 0, 1, 0)
+    }
+
     /// Retrieve the compressed data
     ///
     /// To be called until it returns `None`.
@@ -271,6 +814,57 @@ impl VP9Encoder {
             Some(VPXPacket::new(unsafe { *pkt }))
         }
     }
+
+    /// Iterate over the packets produced by the last encode call
+    ///
+    /// The encoder is borrowed until the iterator is dropped, the packets
+    /// that are not retrieved are lost once more data is sent.
+    ///
+    /// ```compile_fail
+    /// # fn f(enc: &mut libvpx::encoder::VP9Encoder) {
+    /// for pkt in enc.packets() {
+    ///     enc.flush().unwrap();
+    /// }
+    /// # }
+    /// ```
+    pub fn packets(&mut self) -> Packets<'_> {
+        Packets { enc: self }
+    }
+
+    /// Encode a sequence of pictures, flush and return all the packets
+    ///
+    /// Each picture comes with its pts and lasts one timebase unit.
+    pub fn encode_all<'a, I>(&mut self, images: I) -> Result<Vec<VPXPacket>, vpx_codec_err_t>
+    where
+        I: IntoIterator<Item = (InputImage<'a>, i64)>,
+    {
+        let mut packets = Vec::new();
+
+        for (img, pts) in images {
+            self.encode_image(&img, pts, 1, 0)?;
+            packets.extend(self.packets());
+        }
+
+        self.flush()?;
+        packets.extend(self.packets());
+
+        Ok(packets)
+    }
+}
+
+/// Iterator over the available packets, see [`VP9Encoder::packets`]
+///
+/// [`VP9Encoder::packets`]: struct.VP9Encoder.html#method.packets
+pub struct Packets<'a> {
+    enc: &'a mut VP9Encoder,
+}
+
+impl Iterator for Packets<'_> {
+    type Item = VPXPacket;
+
+    fn next(&mut self) -> Option<VPXPacket> {
+        self.enc.get_packet()
+    }
 }
 
 impl Drop for VP9Encoder {
@@ -288,9 +882,11 @@ impl VPXCodec for VP9Encoder {
 #[cfg(feature = "codec-trait")]
 mod encoder_trait {
     use super::*;
+    use crate::common::yuv_formaton;
     use av_codec::encoder::*;
     use av_codec::error::*;
     use av_data::frame::ArcFrame;
+    use av_data::packet::Packet;
     use av_data::params::{CodecParams, MediaKind, VideoInfo};
     use av_data::value::Value;
 
@@ -301,6 +897,7 @@ mod encoder_trait {
     pub struct Enc {
         cfg: VP9EncoderConfig,
         enc: Option<VP9Encoder>,
+        format: Option<Arc<Formaton>>,
     }
 
     impl Descriptor for Des {
@@ -310,6 +907,7 @@ mod encoder_trait {
             Enc {
                 cfg: VP9EncoderConfig::new().unwrap(),
                 enc: None,
+                format: None,
             }
         }
 
@@ -328,18 +926,24 @@ mod encoder_trait {
                     })
                     .map_err(|_err| Error::ConfigurationIncomplete)
             } else {
-                unimplemented!()
+                Err(Error::Unsupported("Reconfiguration".to_owned()))
             }
         }
 
-        // TODO: have it as default impl?
         fn get_extradata(&self) -> Option<Vec<u8>> {
-            None
+            let record = match (&self.enc, self.format.as_deref()) {
+                (Some(enc), Some(format)) => enc.codec_config_record_for(format),
+                (Some(enc), None) => enc.codec_config_record(None),
+                (None, Some(format)) => self.cfg.codec_config_record_for(format),
+                (None, None) => self.cfg.codec_config_record(None),
+            };
+
+            Some(record.to_bytes())
         }
 
         fn send_frame(&mut self, frame: &ArcFrame) -> Result<()> {
             let enc = self.enc.as_mut().unwrap();
-            enc.encode(frame).map_err(|_| unimplemented!())
+            enc.encode(frame).map_err(|_| Error::InvalidData)
         }
 
         fn receive_packet(&mut self) -> Result<Packet> {
@@ -347,8 +951,8 @@ mod encoder_trait {
 
             if let Some(p) = enc.get_packet() {
                 match p {
-                    VPXPacket::Packet(pkt) => Ok(pkt),
-                    _ => unimplemented!(),
+                    VPXPacket::Packet(pkt) => Ok(pkt.into()),
+                    _ => Err(Error::Unsupported("Non-frame packet".to_owned())),
                 }
             } else {
                 Err(Error::MoreDataNeeded)
@@ -357,7 +961,7 @@ mod encoder_trait {
 
         fn flush(&mut self) -> Result<()> {
             let enc = self.enc.as_mut().unwrap();
-            enc.flush().map_err(|_| unimplemented!())
+            enc.flush().map_err(|_| Error::InvalidData)
         }
 
         fn set_option<'a>(&mut self, key: &str, val: Value<'a>) -> Result<()> {
@@ -383,6 +987,10 @@ mod encoder_trait {
                     self.cfg.cfg.g_timebase.den = den as i32;
                     Ok(())
                 }
+                ("render-size", Value::Pair(w, h)) => {
+                    self.cfg.render_size = Some((w as u32, h as u32));
+                    Ok(())
+                }
                 ("lag-in-frames", Value::U64(v)) => {
                     self.cfg.cfg.g_lag_in_frames = v as u32;
                     Ok(())
@@ -417,24 +1025,35 @@ mod encoder_trait {
                             .map_err(|_err| Error::ConfigurationInvalid)
                     })
                 }
-                // ("format", Value::Formaton(f)) => self.format = Some(f),
-                _ => unimplemented!(),
+                ("format", Value::Formaton(f)) => {
+                    self.format = Some(f);
+                    Ok(())
+                }
+                (key, _) => Err(Error::Unsupported(format!("{} key", key))),
             }
         }
 
         fn get_params(&self) -> Result<CodecParams> {
-            use std::sync::Arc;
+            let cfg = &self.cfg.cfg;
+            let format = self.format.clone().unwrap_or_else(|| {
+                // Profiles 1 and 3 carry non-4:2:0 content, assume 4:4:4
+                let shift = if cfg.g_profile & 1 == 0 { 1 } else { 0 };
+                Arc::new(yuv_formaton(shift, shift, cfg.g_bit_depth as u32))
+            });
+
             Ok(CodecParams {
                 kind: Some(MediaKind::Video(VideoInfo {
-                    height: self.cfg.cfg.g_h as usize,
-                    width: self.cfg.cfg.g_w as usize,
-                    format: Some(Arc::new(*YUV420)), // TODO: support more formats
+                    height: cfg.g_h as usize,
+                    width: cfg.g_w as usize,
+                    format: Some(format),
                 })),
                 codec_id: Some("vp9".to_owned()),
-                extradata: None,
-                bit_rate: 0, // TODO: expose the information
+                extradata: self.get_extradata(),
+                bit_rate: cfg.rc_target_bitrate as usize * 1000,
                 convergence_window: 0,
-                delay: 0,
+                // The encoder buffers up to g_lag_in_frames frames before
+                // returning the first packet
+                delay: cfg.g_lag_in_frames as usize,
             })
         }
 
@@ -442,6 +1061,9 @@ mod encoder_trait {
             if let Some(MediaKind::Video(ref info)) = params.kind {
                 self.cfg.cfg.g_w = info.width as u32;
                 self.cfg.cfg.g_h = info.height as u32;
+                if info.format.is_some() {
+                    self.format = info.format.clone();
+                }
             }
             Ok(())
         }
@@ -474,6 +1096,26 @@ pub(crate) mod tests {
         println!("{}", e.error_to_str());
     }
 
+    #[cfg(feature = "av-data")]
+    #[test]
+    fn packet_conversion() {
+        let pkt = EncodedPacket {
+            data: vec![1, 2, 3],
+            pts: 5,
+            duration: 2,
+            is_key: true,
+            is_invisible: false,
+        };
+
+        let p = av_data::packet::Packet::from(pkt.clone());
+        assert_eq!(p.t.pts, Some(5));
+        assert_eq!(p.t.duration, Some(2));
+        assert_eq!(EncodedPacket::from(&p), pkt);
+
+        let p = av_data::packet::Packet::with_capacity(0);
+        assert_eq!(EncodedPacket::from(&p), EncodedPacket::default());
+    }
+
     use super::vp8e_enc_control_id::*;
     #[test]
     fn control() {
@@ -495,14 +1137,12 @@ pub(crate) mod tests {
         e.control(VP8E_SET_CQ_LEVEL, 4).unwrap();
     }
 
-    use av_data::rational::*;
-    use av_data::timeinfo::TimeInfo;
-    pub fn setup(w: u32, h: u32, t: &TimeInfo) -> VP9Encoder {
+    pub fn setup_with_timebase(w: u32, h: u32, num: i32, den: i32) -> VP9Encoder {
         let mut c = VP9EncoderConfig::new().unwrap();
         c.cfg.g_w = w;
         c.cfg.g_h = h;
-        c.cfg.g_timebase.num = *t.timebase.unwrap().numer() as i32;
-        c.cfg.g_timebase.den = *t.timebase.unwrap().denom() as i32;
+        c.cfg.g_timebase.num = num;
+        c.cfg.g_timebase.den = den;
         c.cfg.g_threads = 4;
         c.cfg.g_pass = vpx_enc_pass::VPX_RC_ONE_PASS;
         c.cfg.rc_end_usage = vpx_rc_mode::VPX_CQ;
@@ -514,6 +1154,41 @@ pub(crate) mod tests {
         e
     }
 
+    /// Encode a moving 8-bit 4:2:0 gradient as the `i`-th picture
+    pub fn encode_pattern(e: &mut VP9Encoder, i: i64) {
+        use self::vpx_img_fmt_t::*;
+
+        let (w, h) = (e.cfg.g_w as usize, e.cfg.g_h as usize);
+        let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+        let luma: Vec<u8> = (0..w * h)
+            .map(|j| (j % w + j / w + i as usize * 8) as u8)
+            .collect();
+        let chroma = vec![128u8; cw * ch];
+
+        let img = InputImage {
+            format: ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap(),
+            planes: [&luma, &chroma, &chroma],
+            strides: [w, cw, cw],
+        };
+
+        e.encode_image(&img, i, 1, 0).unwrap();
+    }
+
+    #[cfg(feature = "av-data")]
+    use av_data::rational::*;
+    #[cfg(feature = "av-data")]
+    use av_data::timeinfo::TimeInfo;
+    #[cfg(feature = "av-data")]
+    pub fn setup(w: u32, h: u32, t: &TimeInfo) -> VP9Encoder {
+        setup_with_timebase(
+            w,
+            h,
+            *t.timebase.unwrap().numer() as i32,
+            *t.timebase.unwrap().denom() as i32,
+        )
+    }
+
+    #[cfg(feature = "av-data")]
     pub fn setup_frame(w: u32, h: u32, t: &TimeInfo) -> Frame {
         use av_data::frame::*;
         use av_data::pixel::formats;
@@ -530,6 +1205,7 @@ pub(crate) mod tests {
         Frame::new_default_frame(v, Some(t.clone()))
     }
 
+    #[cfg(feature = "av-data")]
     #[test]
     fn encode() {
         let w = 200;
@@ -567,6 +1243,191 @@ pub(crate) mod tests {
         if out != 1 {
             panic!("No packet produced");
         }
+
+        let f = setup_frame(w / 2, h, &t);
+        assert_eq!(e.encode(&f), Err(VPX_CODEC_INVALID_PARAM));
+    }
+
+    #[test]
+    fn encode_image() {
+        use self::vpx_img_fmt_t::*;
+
+        let mut e = setup_with_timebase(64, 48, 1, 1000);
+        let format = ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap();
+        let luma = vec![128u8; 64 * 48];
+        let chroma = vec![128u8; 32 * 24];
+
+        let mut img = InputImage {
+            format,
+            planes: [&luma, &chroma, &chroma],
+            strides: [64, 32, 32],
+        };
+
+        for i in 0..10 {
+            e.encode_image(&img, i, 1, 0).unwrap();
+        }
+
+        img.planes[2] = &chroma[..32 * 23];
+        assert_eq!(e.encode_image(&img, 10, 1, 0), Err(VPX_CODEC_INVALID_PARAM));
+
+        img.planes[2] = &chroma;
+        img.strides[0] = 32;
+        assert_eq!(e.encode_image(&img, 10, 1, 0), Err(VPX_CODEC_INVALID_PARAM));
+
+        e.flush().unwrap();
+
+        let mut out = 0;
+        while let Some(p) = e.get_packet() {
+            if let VPXPacket::Packet(_) = p {
+                out += 1;
+            }
+        }
+        assert_eq!(out, 10);
+    }
+
+    #[test]
+    fn encode_vpx_image() {
+        use self::vpx_img_fmt_t::*;
+
+        let mut e = setup_with_timebase(64, 48, 1, 1000);
+        let format = ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap();
+        let mut img = Image::new(format, 80, 48, 32).unwrap();
+
+        assert_eq!(
+            e.encode_vpx_image(&img, 0, 1, 0),
+            Err(VPX_CODEC_INVALID_PARAM)
+        );
+
+        img.set_rect(8, 0, 64, 48).unwrap();
+        for i in 0..10 {
+            img.plane_mut(0).iter_mut().for_each(|b| *b = i as u8 * 16);
+            e.encode_vpx_image(&img, i, 1, 0).unwrap();
+        }
+        img.flip();
+        e.encode_vpx_image(&img, 10, 1, 0).unwrap();
+        e.flush().unwrap();
+
+        let mut out = 0;
+        while let Some(p) = e.get_packet() {
+            if let VPXPacket::Packet(_) = p {
+                out += 1;
+            }
+        }
+        assert_eq!(out, 11);
+    }
+
+    #[test]
+    fn encode_planes() {
+        use self::vpx_img_fmt_t::*;
+
+        let (w, h) = (64, 48);
+
+        let mut c = VP9EncoderConfig::new().unwrap();
+        c.cfg.g_w = w;
+        c.cfg.g_h = h;
+        c.cfg.g_timebase.num = 1;
+        c.cfg.g_timebase.den = 1000;
+        c.cfg.g_profile = 2;
+        c.cfg.g_bit_depth = vpx_bit_depth::VPX_BITS_10;
+        c.cfg.g_input_bit_depth = 10;
+
+        let mut e = c.get_encoder().unwrap();
+        let format = ImageFormat::new(VPX_IMG_FMT_I42016, 10).unwrap();
+        let luma = vec![512u16; 64 * 48];
+        let chroma = vec![512u16; 32 * 24];
+        let planes = [&luma[..], &chroma[..], &chroma[..]];
+
+        for i in 0..5 {
+            e.encode_planes_u16(&planes, [64, 32, 32], format, i, 1, 0)
+                .unwrap();
+        }
+
+        assert_eq!(
+            e.encode_planes_u16(&planes, [64, 33, 32], format, 5, 1, 0),
+            Err(VPX_CODEC_INVALID_PARAM)
+        );
+
+        let bytes = vec![0u8; 64 * 48 * 2];
+        let planes = [&bytes[..], &bytes[..], &bytes[..]];
+        assert_eq!(
+            e.encode_planes(&planes, [128, 64, 64], format, 5, 1, 0),
+            Err(VPX_CODEC_INVALID_PARAM)
+        );
+
+        e.flush().unwrap();
+
+        let mut out = 0;
+        while let Some(p) = e.get_packet() {
+            if let VPXPacket::Packet(_) = p {
+                out += 1;
+            }
+        }
+        assert_eq!(out, 5);
+        assert_eq!(e.codec_config_record(None).bit_depth, 10);
+    }
+
+    #[test]
+    fn two_pass() {
+        let config = |pass| {
+            let mut c = VP9EncoderConfig::new().unwrap();
+            c.cfg.g_w = 64;
+            c.cfg.g_h = 48;
+            c.cfg.g_timebase.num = 1;
+            c.cfg.g_timebase.den = 1000;
+            c.cfg.g_pass = pass;
+            // Whatever the raw field holds is ignored.
+            c.cfg.rc_twopass_stats_in.buf = ptr::NonNull::dangling().as_ptr();
+            c.cfg.rc_twopass_stats_in.sz = 1 << 20;
+            c
+        };
+
+        let mut e = config(vpx_enc_pass::VPX_RC_FIRST_PASS)
+            .get_encoder()
+            .unwrap();
+        let mut stats = Vec::new();
+        for i in 0..6 {
+            match i {
+                5 => e.flush().unwrap(),
+                _ => encode_pattern(&mut e, i),
+            }
+            for p in e.packets() {
+                if let VPXPacket::Stats(s) = p {
+                    stats.extend_from_slice(&s);
+                }
+            }
+        }
+        assert!(!stats.is_empty());
+
+        let mut c = config(vpx_enc_pass::VPX_RC_LAST_PASS);
+        assert!(c.get_encoder().is_err());
+
+        c.twopass_stats = Some(stats.into());
+        let mut e = c.get_encoder().unwrap();
+        // The stats stay alive as long as the encoder needs them.
+        drop(c);
+
+        let mut out = 0;
+        for i in 0..6 {
+            match i {
+                5 => e.flush().unwrap(),
+                _ => encode_pattern(&mut e, i),
+            }
+            out += e
+                .packets()
+                .filter(|p| matches!(p, VPXPacket::Packet(_)))
+                .count();
+        }
+        assert_eq!(out, 5);
+    }
+
+    #[test]
+    fn send_sync() {
+        fn is_send<T: Send>() {}
+        fn is_sync<T: Sync>() {}
+
+        is_send::<VP9EncoderConfig>();
+        is_sync::<VP9EncoderConfig>();
+        is_send::<VP9Encoder>();
     }
 
     #[cfg(all(test, feature = "codec-trait"))]
@@ -576,6 +1437,8 @@ pub(crate) mod tests {
         use av_codec::common::CodecList;
         use av_codec::encoder::*;
         use av_codec::error::*;
+        use av_data::params::MediaKind;
+        use av_data::pixel::formats;
         use std::sync::Arc;
 
         let encoders = Codecs::from_list(&[VP9_DESCR]);
@@ -600,6 +1463,28 @@ pub(crate) mod tests {
 
         ctx.configure().unwrap();
 
+        let extradata = ctx.get_extradata().unwrap();
+        let record = VPCodecConfigurationRecord::from_bytes(&extradata).unwrap();
+        assert_eq!(record.profile, 0);
+        assert_eq!(record.bit_depth, 8);
+        assert_eq!(
+            record.chroma_subsampling,
+            ChromaSubsampling::Yuv420Colocated
+        );
+
+        let params = ctx.get_params().unwrap();
+        let default_cfg = VP9EncoderConfig::new().unwrap().cfg;
+        assert_eq!(params.delay, 0);
+        assert_eq!(
+            params.bit_rate,
+            default_cfg.rc_target_bitrate as usize * 1000
+        );
+        if let Some(MediaKind::Video(info)) = params.kind {
+            assert_eq!(info.format.as_deref(), Some(formats::YUV420));
+        } else {
+            panic!("No video information");
+        }
+
         ctx.set_option("cpu-used", 2u64).unwrap();
         ctx.set_option("auto-alt-ref", 1u64).unwrap();
         ctx.set_option("arnr-maxframes", 5u64).unwrap();
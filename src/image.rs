@@ -0,0 +1,479 @@
+//! Owned uncompressed pictures
+//!
+//! [`Image`] wraps a `vpx_image` allocated by libvpx, or describing a
+//! caller provided buffer, and frees it on drop.
+
+use crate::common::ImageFormat;
+use crate::ffi::*;
+
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use self::vpx_codec_err_t::*;
+
+/// Uncompressed planar YUV picture
+///
+/// It can be sent to [`VP9Encoder::encode_vpx_image`] and built from a
+/// decoded picture with [`DecodedImage::to_image`].
+///
+/// [`VP9Encoder::encode_vpx_image`]: ../encoder/struct.VP9Encoder.html#method.encode_vpx_image
+/// [`DecodedImage::to_image`]: ../decoder/struct.DecodedImage.html#method.to_image
+#[derive(Debug)]
+pub struct Image {
+    img: NonNull<vpx_image>,
+    format: ImageFormat,
+    // Backs the planes of a wrapped image, it must outlive `img`.
+    _data: Option<Box<[u8]>>,
+}
+
+// The image owns its descriptor and samples, they are only reachable
+// through it and mutated through `&mut self`.
+unsafe impl Send for Image {}
+unsafe impl Sync for Image {}
+
+fn check_align(align: u32) -> Result<u32, vpx_codec_err_t> {
+    match align {
+        0 => Ok(1),
+        a if a.is_power_of_two() => Ok(a),
+        _ => Err(VPX_CODEC_INVALID_PARAM),
+    }
+}
+
+impl Image {
+    /// Allocate a `w`x`h` picture
+    ///
+    /// The buffer and the strides are aligned to `align` bytes, which must
+    /// be a power of two or 0. The samples are left uninitialized by
+    /// libvpx, so they are zeroed.
+    ///
+    /// It calls `vpx_img_alloc`.
+    pub fn new(format: ImageFormat, w: u32, h: u32, align: u32) -> Result<Image, vpx_codec_err_t> {
+        let align = check_align(align)?;
+        let img = unsafe { vpx_img_alloc(ptr::null_mut(), format.fmt(), w, h, align) };
+        let img = Image::from_raw(img, format, None)?;
+
+        for idx in 0..img.plane_count() {
+            let (offset, len) = img.plane_extent(idx);
+            unsafe { ptr::write_bytes(img.img().planes[idx].offset(offset), 0, len) };
+        }
+
+        Ok(img)
+    }
+
+    /// Describe a `w`x`h` picture stored in `data`
+    ///
+    /// The planes follow each other in `data` with the layout produced
+    /// by [`new`], the strides are aligned to `align` bytes.
+    ///
+    /// It fails with `VPX_CODEC_INVALID_PARAM` if `data` is too small or
+    /// if it is not suitably aligned for 16-bit samples.
+    ///
+    /// It calls `vpx_img_wrap`.
+    ///
+    /// [`new`]: #method.new
+    pub fn wrap(
+        format: ImageFormat,
+        w: u32,
+        h: u32,
+        align: u32,
+        mut data: Box<[u8]>,
+    ) -> Result<Image, vpx_codec_err_t> {
+        let align = check_align(align)?;
+        if format.is_high_bitdepth() && data.as_ptr().align_offset(2) != 0 {
+            return Err(VPX_CODEC_INVALID_PARAM);
+        }
+
+        let range = data.as_ptr_range();
+        let img = unsafe {
+            vpx_img_wrap(
+                ptr::null_mut(),
+                format.fmt(),
+                w,
+                h,
+                align,
+                data.as_mut_ptr(),
+            )
+        };
+        let img = Image::from_raw(img, format, Some(data))?;
+
+        let fits = (0..img.plane_count()).all(|idx| {
+            let (offset, len) = img.plane_extent(idx);
+            let start = unsafe { img.img().planes[idx].offset(offset) } as *const u8;
+
+            range.start <= start && range.end as usize - start as usize >= len
+        });
+
+        if fits {
+            Ok(img)
+        } else {
+            Err(VPX_CODEC_INVALID_PARAM)
+        }
+    }
+
+    fn from_raw(
+        img: *mut vpx_image,
+        format: ImageFormat,
+        data: Option<Box<[u8]>>,
+    ) -> Result<Image, vpx_codec_err_t> {
+        let mut img = NonNull::new(img).ok_or(VPX_CODEC_MEM_ERROR)?;
+
+        // libvpx only knows about 8 and 16 bits.
+        unsafe { img.as_mut().bit_depth = format.bit_depth() };
+
+        Ok(Image {
+            img,
+            format,
+            _data: data,
+        })
+    }
+
+    fn img(&self) -> &vpx_image {
+        unsafe { self.img.as_ref() }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const vpx_image {
+        self.img.as_ptr()
+    }
+
+    /// Displayed width
+    pub fn width(&self) -> u32 {
+        self.img().d_w
+    }
+
+    /// Displayed height
+    pub fn height(&self) -> u32 {
+        self.img().d_h
+    }
+
+    /// Layout of the samples
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Color space of the samples
+    pub fn color_space(&self) -> vpx_color_space_t {
+        self.img().cs
+    }
+
+    /// Color range of the samples
+    pub fn color_range(&self) -> vpx_color_range_t {
+        self.img().range
+    }
+
+    /// Set the color space and range of the samples
+    pub fn set_color(&mut self, cs: vpx_color_space_t, range: vpx_color_range_t) {
+        let img = unsafe { self.img.as_mut() };
+
+        img.cs = cs;
+        img.range = range;
+    }
+
+    /// Number of planes
+    pub fn plane_count(&self) -> usize {
+        3
+    }
+
+    /// Distance in bytes between two rows of the `idx`-th plane
+    ///
+    /// It is negative once the image is flipped.
+    pub fn stride(&self, idx: usize) -> isize {
+        self.img().stride[idx] as isize
+    }
+
+    /// Width and height in samples of the `idx`-th plane
+    pub fn plane_size(&self, idx: usize) -> (usize, usize) {
+        self.format.plane_size(idx, self.width(), self.height())
+    }
+
+    // Offset from the plane pointer to the lowest address of the visible
+    // samples and the number of bytes up to the end of the last row.
+    fn plane_extent(&self, idx: usize) -> (isize, usize) {
+        let (w, h) = self.plane_size(idx);
+        let stride = self.stride(idx);
+        let row = w * self.format.bytes_per_sample();
+
+        match h {
+            0 => (0, 0),
+            _ if stride < 0 => (
+                stride * (h - 1) as isize,
+                stride.unsigned_abs() * (h - 1) + row,
+            ),
+            _ => (0, stride as usize * (h - 1) + row),
+        }
+    }
+
+    /// Samples of the `idx`-th plane, from the lowest to the highest address
+    ///
+    /// High bit depth samples are native endian `u16`s, see [`plane_u16`].
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is not lower than [`plane_count`].
+    ///
+    /// [`plane_u16`]: #method.plane_u16
+    /// [`plane_count`]: #method.plane_count
+    pub fn plane(&self, idx: usize) -> &[u8] {
+        assert!(idx < self.plane_count());
+
+        let (offset, len) = self.plane_extent(idx);
+
+        unsafe { slice::from_raw_parts(self.img().planes[idx].offset(offset), len) }
+    }
+
+    /// Mutable samples of the `idx`-th plane
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is not lower than [`plane_count`].
+    ///
+    /// [`plane_count`]: #method.plane_count
+    pub fn plane_mut(&mut self, idx: usize) -> &mut [u8] {
+        assert!(idx < self.plane_count());
+
+        let (offset, len) = self.plane_extent(idx);
+
+        unsafe { slice::from_raw_parts_mut(self.img().planes[idx].offset(offset), len) }
+    }
+
+    /// Samples of the `idx`-th plane of a high bit depth image
+    ///
+    /// # Panics
+    ///
+    /// If the samples are stored in 8 bits or `idx` is not lower than
+    /// [`plane_count`].
+    ///
+    /// [`plane_count`]: #method.plane_count
+    pub fn plane_u16(&self, idx: usize) -> &[u16] {
+        assert!(self.format.is_high_bitdepth());
+
+        // Both the buffer and the strides are even.
+        let (pre, samples, post) = unsafe { self.plane(idx).align_to::<u16>() };
+        assert!(pre.is_empty() && post.is_empty());
+
+        samples
+    }
+
+    /// Mutable samples of the `idx`-th plane of a high bit depth image
+    ///
+    /// # Panics
+    ///
+    /// If the samples are stored in 8 bits or `idx` is not lower than
+    /// [`plane_count`].
+    ///
+    /// [`plane_count`]: #method.plane_count
+    pub fn plane_u16_mut(&mut self, idx: usize) -> &mut [u16] {
+        assert!(self.format.is_high_bitdepth());
+
+        let (pre, samples, post) = unsafe { self.plane_mut(idx).align_to_mut::<u16>() };
+        assert!(pre.is_empty() && post.is_empty());
+
+        samples
+    }
+
+    /// Visible rows of the `idx`-th plane, from top to bottom
+    pub fn rows(&self, idx: usize) -> impl Iterator<Item = &[u8]> {
+        let data = self.plane(idx);
+        let (w, h) = self.plane_size(idx);
+        let stride = self.stride(idx);
+        let step = stride.unsigned_abs();
+        let row = w * self.format.bytes_per_sample();
+
+        (0..h).map(move |y| {
+            let y = if stride < 0 { h - 1 - y } else { y };
+            &data[y * step..y * step + row]
+        })
+    }
+
+    /// Mutable visible rows of the `idx`-th plane, from top to bottom
+    pub fn rows_mut(&mut self, idx: usize) -> impl Iterator<Item = &mut [u8]> {
+        let (w, _) = self.plane_size(idx);
+        let stride = self.stride(idx);
+        let row = w * self.format.bytes_per_sample();
+
+        let mut rows: Vec<_> = match stride.unsigned_abs() {
+            0 => Vec::new(),
+            step => self
+                .plane_mut(idx)
+                .chunks_mut(step)
+                .map(|r| &mut r[..row])
+                .collect(),
+        };
+        if stride < 0 {
+            rows.reverse();
+        }
+
+        rows.into_iter()
+    }
+
+    /// Restrict the visible area to the `w`x`h` rectangle at `x`,`y`
+    ///
+    /// The rectangle is relative to the whole allocated picture, it fails
+    /// with `VPX_CODEC_INVALID_PARAM` if it does not fit or if the image
+    /// is flipped.
+    ///
+    /// It calls `vpx_img_set_rect`.
+    pub fn set_rect(&mut self, x: u32, y: u32, w: u32, h: u32) -> Result<(), vpx_codec_err_t> {
+        // libvpx recomputes the planes assuming top-down rows.
+        if self.stride(0) < 0 {
+            return Err(VPX_CODEC_INVALID_PARAM);
+        }
+
+        let ret = unsafe { vpx_img_set_rect(self.img.as_ptr(), x, y, w, h) };
+
+        match ret {
+            0 => Ok(()),
+            _ => Err(VPX_CODEC_INVALID_PARAM),
+        }
+    }
+
+    /// Flip the picture upside down without moving the samples
+    ///
+    /// It does what `vpx_img_flip` does, except that the chroma planes of
+    /// odd height pictures keep their last row: libvpx rounds the chroma
+    /// height down there, leaving the bottom row outside the flipped plane.
+    pub fn flip(&mut self) {
+        for idx in 0..self.plane_count() {
+            let (_, h) = self.plane_size(idx);
+            let stride = self.stride(idx);
+            let img = unsafe { self.img.as_mut() };
+
+            // Point to the last visible row, the first one once flipped.
+            if h > 0 {
+                img.planes[idx] = unsafe { img.planes[idx].offset(stride * (h - 1) as isize) };
+            }
+            img.stride[idx] = -img.stride[idx];
+        }
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe { vpx_img_free(self.img.as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::vpx_img_fmt_t::*;
+    use super::*;
+
+    #[test]
+    fn alloc() {
+        let format = ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap();
+        let mut img = Image::new(format, 35, 17, 16).unwrap();
+
+        assert_eq!((img.width(), img.height()), (35, 17));
+        assert_eq!(img.plane_size(1), (18, 9));
+        assert_eq!(img.stride(0) % 16, 0);
+        assert!(img.plane(0).iter().all(|&b| b == 0));
+
+        for (y, row) in img.rows_mut(2).enumerate() {
+            row.iter_mut().for_each(|b| *b = y as u8);
+        }
+        assert_eq!(img.rows(2).count(), 9);
+        assert!(img
+            .rows(2)
+            .enumerate()
+            .all(|(y, r)| r.len() == 18 && r[17] == y as u8));
+
+        assert_eq!(
+            Image::new(format, 35, 17, 3).err(),
+            Some(VPX_CODEC_INVALID_PARAM)
+        );
+    }
+
+    #[test]
+    fn wrap() {
+        let format = ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap();
+        let data = vec![7u8; 16 * 16 * 3 / 2].into_boxed_slice();
+        let img = Image::wrap(format, 16, 16, 1, data).unwrap();
+
+        assert_eq!(img.stride(0), 16);
+        assert_eq!(img.plane(2).len(), 8 * 8);
+        assert!(img.rows(1).all(|r| r == [7u8; 8]));
+
+        let data = vec![0u8; 16 * 16].into_boxed_slice();
+        assert_eq!(
+            Image::wrap(format, 16, 16, 1, data).err(),
+            Some(VPX_CODEC_INVALID_PARAM)
+        );
+    }
+
+    #[test]
+    fn rect_and_flip() {
+        let format = ImageFormat::new(VPX_IMG_FMT_I444, 8).unwrap();
+        let mut img = Image::new(format, 8, 4, 1).unwrap();
+
+        for (y, row) in img.rows_mut(0).enumerate() {
+            row.iter_mut().for_each(|b| *b = y as u8);
+        }
+
+        img.set_rect(2, 1, 4, 2).unwrap();
+        assert_eq!((img.width(), img.height()), (4, 2));
+        assert!(img.rows(0).eq([[1u8; 4], [2u8; 4]].iter().map(|r| &r[..])));
+        assert!(img.set_rect(6, 0, 4, 2).is_err());
+
+        img.flip();
+        assert!(img.stride(0) < 0);
+        assert!(img.rows(0).eq([[2u8; 4], [1u8; 4]].iter().map(|r| &r[..])));
+        assert_eq!(img.rows_mut(0).next().unwrap()[0], 2);
+        assert!(img.set_rect(0, 0, 4, 2).is_err());
+
+        img.flip();
+        assert!(img.rows(0).eq([[1u8; 4], [2u8; 4]].iter().map(|r| &r[..])));
+    }
+
+    #[test]
+    fn flip_odd_height() {
+        for &fmt in &[VPX_IMG_FMT_I420, VPX_IMG_FMT_I440, VPX_IMG_FMT_I42016] {
+            let format = ImageFormat::new(fmt, 8).unwrap();
+            let mut img = Image::new(format, 7, 5, 1).unwrap();
+            let bps = format.bytes_per_sample();
+
+            for idx in 0..img.plane_count() {
+                for (y, row) in img.rows_mut(idx).enumerate() {
+                    row.iter_mut().for_each(|b| *b = y as u8);
+                }
+            }
+
+            img.flip();
+            for idx in 0..img.plane_count() {
+                let (w, h) = img.plane_size(idx);
+                assert_eq!(
+                    img.plane(idx).len(),
+                    img.stride(idx).unsigned_abs() * (h - 1) + w * bps
+                );
+                assert!(img
+                    .rows(idx)
+                    .enumerate()
+                    .all(|(y, r)| r.iter().all(|&b| b == (h - 1 - y) as u8)));
+
+                for row in img.rows_mut(idx) {
+                    row.iter_mut().for_each(|b| *b = !*b);
+                }
+                img.plane_mut(idx).iter_mut().for_each(|b| *b = !*b);
+            }
+
+            img.flip();
+            for idx in 0..img.plane_count() {
+                assert!(img
+                    .rows(idx)
+                    .enumerate()
+                    .all(|(y, r)| r.iter().all(|&b| b == y as u8)));
+            }
+        }
+    }
+
+    #[test]
+    fn high_bitdepth() {
+        let format = ImageFormat::new(VPX_IMG_FMT_I42016, 10).unwrap();
+        let mut img = Image::new(format, 16, 16, 32).unwrap();
+
+        assert_eq!(img.format().bit_depth(), 10);
+        assert_eq!(img.rows(0).next().unwrap().len(), 32);
+
+        img.plane_u16_mut(0)[0] = 1023;
+        assert_eq!(img.plane_u16(0)[0], 1023);
+        assert_eq!(img.plane_u16(1).len() * 2, img.plane(1).len());
+    }
+}
@@ -0,0 +1,386 @@
+//! IVF container
+//!
+//! The simple container used by the libvpx tools: a 32 bytes file header
+//! followed by frames, each prefixed by its size and pts.
+
+use crate::common::VPXCodecId;
+use crate::encoder::{VP9EncoderConfig, VPXPacket};
+use crate::util::{invalid_data, read_exact_untrusted};
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const SIGNATURE: &[u8; 4] = b"DKIF";
+const HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 12;
+const FRAME_COUNT_OFFSET: u64 = 24;
+
+fn fourcc(codec: VPXCodecId) -> &'static [u8; 4] {
+    match codec {
+        VPXCodecId::VP8 => b"VP80",
+        VPXCodecId::VP9 => b"VP90",
+    }
+}
+
+/// IVF file header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub codec: VPXCodecId,
+    pub width: u16,
+    pub height: u16,
+    /// Timebase of the pts, as numerator and denominator
+    pub timebase: (u32, u32),
+    /// Number of frames, as stored in the file
+    pub frame_count: u32,
+}
+
+impl Header {
+    /// Describe the stream a VP9 encoder configuration produces
+    ///
+    /// The header is always VP9, as [`VP9EncoderConfig`] only drives the
+    /// VP9 encoder. Build the `Header` directly to describe a VP8 stream.
+    ///
+    /// It fails with `InvalidInput` if the size does not fit the 16 bits
+    /// fields of the header.
+    pub fn from_vp9_config(cfg: &VP9EncoderConfig) -> io::Result<Header> {
+        let size = |v: u32| {
+            u16::try_from(v).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Picture too large for IVF")
+            })
+        };
+
+        Ok(Header {
+            codec: VPXCodecId::VP9,
+            width: size(cfg.cfg.g_w)?,
+            height: size(cfg.cfg.g_h)?,
+            timebase: (cfg.cfg.g_timebase.num as u32, cfg.cfg.g_timebase.den as u32),
+            frame_count: 0,
+        })
+    }
+
+    fn parse(buf: &[u8; HEADER_SIZE]) -> io::Result<Header> {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        if &buf[0..4] != SIGNATURE {
+            return Err(invalid_data("Not an IVF file"));
+        }
+        if u16_at(4) != 0 {
+            return Err(invalid_data("Unsupported IVF version"));
+        }
+        if u16_at(6) as usize != HEADER_SIZE {
+            return Err(invalid_data("Unsupported IVF header size"));
+        }
+
+        let codec = match &buf[8..12] {
+            b"VP80" => VPXCodecId::VP8,
+            b"VP90" => VPXCodecId::VP9,
+            _ => return Err(invalid_data("Unsupported IVF fourcc")),
+        };
+
+        Ok(Header {
+            codec,
+            width: u16_at(12),
+            height: u16_at(14),
+            timebase: (u32_at(20), u32_at(16)),
+            frame_count: u32_at(24),
+        })
+    }
+
+    fn serialize(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+
+        buf[0..4].copy_from_slice(SIGNATURE);
+        buf[6..8].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(fourcc(self.codec));
+        buf[12..14].copy_from_slice(&self.width.to_le_bytes());
+        buf[14..16].copy_from_slice(&self.height.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.timebase.1.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.timebase.0.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.frame_count.to_le_bytes());
+
+        buf
+    }
+}
+
+/// Compressed frame stored in an IVF file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    /// Presentation timestamp, in timebase units
+    pub pts: i64,
+}
+
+#[cfg(feature = "av-data")]
+impl From<Packet> for av_data::packet::Packet {
+    fn from(pkt: Packet) -> Self {
+        let mut p = av_data::packet::Packet::new();
+
+        p.data = pkt.data;
+        p.t.pts = Some(pkt.pts);
+
+        p
+    }
+}
+
+/// IVF file reader
+///
+/// It is an iterator over the packets of the file.
+pub struct Reader<R> {
+    inner: R,
+    header: Header,
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the file header
+    ///
+    /// It fails with `InvalidData` if the file is not an IVF file storing
+    /// VP8 or VP9.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut buf = [0; HEADER_SIZE];
+        inner.read_exact(&mut buf)?;
+        let header = Header::parse(&buf)?;
+
+        Ok(Reader { inner, header })
+    }
+
+    /// Return the file header
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Read the next packet
+    ///
+    /// It returns `None` at the end of the file and fails with
+    /// `UnexpectedEof` if the last packet is truncated.
+    pub fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        let mut buf = [0; FRAME_HEADER_SIZE];
+        let mut read = 0;
+        while read < buf.len() {
+            match self.inner.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let size = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as u64;
+        let mut pts = [0; 8];
+        pts.copy_from_slice(&buf[4..]);
+
+        let data = read_exact_untrusted(&mut self.inner, size)?;
+
+        Ok(Some(Packet {
+            data,
+            pts: i64::from_le_bytes(pts),
+        }))
+    }
+
+    /// Return the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_packet().transpose()
+    }
+}
+
+/// IVF file writer
+///
+/// The frame count in the header is updated by [`finish`], a file
+/// dropped without it reports no frames.
+///
+/// [`finish`]: #method.finish
+pub struct Writer<W: Write + Seek> {
+    inner: W,
+    start: u64,
+    frame_count: u32,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Write the file header at the current position
+    pub fn new(mut inner: W, header: &Header) -> io::Result<Self> {
+        let start = inner.stream_position()?;
+        inner.write_all(&header.serialize())?;
+
+        Ok(Writer {
+            inner,
+            start,
+            frame_count: 0,
+        })
+    }
+
+    /// Write a compressed frame
+    ///
+    /// It fails with `InvalidInput` if the frame is larger than 4GiB.
+    pub fn write_frame(&mut self, data: &[u8], pts: i64) -> io::Result<()> {
+        if data.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "IVF frames are limited to 4GiB",
+            ));
+        }
+
+        let mut buf = [0; FRAME_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        buf[4..].copy_from_slice(&pts.to_le_bytes());
+
+        self.inner.write_all(&buf)?;
+        self.inner.write_all(data)?;
+        self.frame_count = self.frame_count.saturating_add(1);
+
+        Ok(())
+    }
+
+    /// Write the frame carried by an encoder packet
+    ///
+    /// Statistics and the other kinds of packets are skipped.
+    pub fn write_packet(&mut self, pkt: &VPXPacket) -> io::Result<()> {
+        match pkt {
+            VPXPacket::Packet(pkt) => self.write_frame(&pkt.data, pkt.pts),
+            _ => Ok(()),
+        }
+    }
+
+    /// Update the frame count in the header and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let end = self.inner.stream_position()?;
+
+        self.inner
+            .seek(SeekFrom::Start(self.start + FRAME_COUNT_OFFSET))?;
+        self.inner.write_all(&self.frame_count.to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::VP9Decoder;
+    use crate::encoder::tests as enc;
+    use std::io::Cursor;
+
+    fn header() -> Header {
+        Header {
+            codec: VPXCodecId::VP9,
+            width: 64,
+            height: 48,
+            timebase: (1, 30),
+            frame_count: 0,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut w = Writer::new(Cursor::new(Vec::new()), &header()).unwrap();
+        w.write_frame(&[1, 2, 3], 0).unwrap();
+        w.write_frame(&[], 1).unwrap();
+        w.write_frame(&[4; 100], -5).unwrap();
+        let buf = w.finish().unwrap().into_inner();
+
+        assert_eq!(&buf[..4], b"DKIF");
+        assert_eq!(&buf[8..12], b"VP90");
+        assert_eq!(buf.len(), 32 + 3 * 12 + 103);
+
+        let mut r = Reader::new(Cursor::new(buf)).unwrap();
+        let expected = Header {
+            frame_count: 3,
+            ..header()
+        };
+        assert_eq!(r.header(), &expected);
+
+        let packets: Vec<_> = r.by_ref().map(Result::unwrap).collect();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].data, [1, 2, 3]);
+        assert_eq!(packets[1].pts, 1);
+        assert_eq!(packets[2].pts, -5);
+        assert!(r.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        let buf = Writer::new(Cursor::new(Vec::new()), &header())
+            .unwrap()
+            .finish()
+            .unwrap()
+            .into_inner();
+
+        let mut bad = buf.clone();
+        bad[0] = b'X';
+        let err = Reader::new(Cursor::new(bad)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut bad = buf.clone();
+        bad[8..12].copy_from_slice(b"AV01");
+        let err = Reader::new(Cursor::new(bad)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = Reader::new(Cursor::new(&buf[..20])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A frame header promising more data than available
+        let mut truncated = buf;
+        truncated.extend_from_slice(&[10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        let mut r = Reader::new(Cursor::new(truncated)).unwrap();
+        let err = r.read_packet().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn from_vp9_config() {
+        let mut c = VP9EncoderConfig::new().unwrap();
+        c.cfg.g_w = 64;
+        c.cfg.g_h = 48;
+        c.cfg.g_timebase.num = 1;
+        c.cfg.g_timebase.den = 30;
+        assert_eq!(Header::from_vp9_config(&c).unwrap(), header());
+
+        c.cfg.g_w = 65536;
+        let err = Header::from_vp9_config(&c).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        let header = Header {
+            timebase: (1, 1000),
+            ..header()
+        };
+        let mut w = Writer::new(Cursor::new(Vec::new()), &header).unwrap();
+
+        for i in 0..6 {
+            match i {
+                5 => e.flush().unwrap(),
+                _ => enc::encode_pattern(&mut e, i),
+            }
+            for pkt in e.packets() {
+                w.write_packet(&pkt).unwrap();
+            }
+        }
+        let buf = w.finish().unwrap().into_inner();
+
+        let r = Reader::new(Cursor::new(buf)).unwrap();
+        assert_eq!(r.header().frame_count, 5);
+
+        let mut d = VP9Decoder::new().unwrap();
+        let frames = d
+            .decode_all(r.map(Result::unwrap).map(|pkt| (pkt.data, Some(pkt.pts))))
+            .unwrap();
+
+        assert_eq!(frames.len(), 5);
+        for (pts, (_, private)) in frames.iter().enumerate() {
+            assert_eq!(private.as_deref(), Some(&(pts as i64)));
+        }
+    }
+}
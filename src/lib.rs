@@ -3,3 +3,14 @@ extern crate vpx_sys as ffi;
 pub mod common;
 pub mod decoder;
 pub mod encoder;
+pub mod image;
+pub mod ivf;
+mod pool;
+mod util;
+pub mod vpcc;
+#[cfg(feature = "webm")]
+pub mod webm;
+#[cfg(feature = "async")]
+pub mod worker;
+#[cfg(feature = "av-data")]
+pub mod y4m;
@@ -0,0 +1,272 @@
+//! Reference counted frame buffers for zero-copy decoding
+//!
+//! libvpx asks for frame buffers through `vpx_codec_set_frame_buffer_functions`
+//! and keeps them as long as they are used as references. Every buffer
+//! handed out is an `Arc` whose ownership is shared between libvpx and the
+//! decoded frames pointing to it, the allocation goes back to the pool
+//! once the last owner drops it.
+
+use crate::ffi::*;
+
+use std::mem::ManuallyDrop;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+/// Pool of recycled frame buffer allocations
+#[derive(Debug, Default)]
+pub(crate) struct FramePool {
+    free: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl FramePool {
+    // Never panic, the lock is taken from within the libvpx callbacks.
+    fn free_list(&self) -> MutexGuard<'_, Vec<Box<[u8]>>> {
+        self.free.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Return a buffer of at least `min_size` bytes
+    ///
+    /// Fresh allocations are zeroed as libvpx requires, recycled ones
+    /// hold the samples of a previous picture.
+    fn get(self: &Arc<FramePool>, min_size: usize) -> Arc<PooledBuffer> {
+        let data = {
+            let mut free = self.free_list();
+            match free.iter().position(|b| b.len() >= min_size) {
+                Some(idx) => free.swap_remove(idx),
+                None => {
+                    // The picture grew, the smaller buffers are of no use anymore.
+                    free.clear();
+                    vec![0u8; min_size].into_boxed_slice()
+                }
+            }
+        };
+
+        let len = data.len();
+        let data = Box::into_raw(data) as *mut u8;
+
+        Arc::new(PooledBuffer {
+            data,
+            len,
+            pool: Arc::downgrade(self),
+        })
+    }
+
+    #[cfg(test)]
+    fn free_count(&self) -> usize {
+        self.free_list().len()
+    }
+}
+
+/// A frame buffer allocation owned by libvpx and/or decoded frames
+#[derive(Debug)]
+pub(crate) struct PooledBuffer {
+    data: *mut u8,
+    len: usize,
+    pool: Weak<FramePool>,
+}
+
+// libvpx writes to the buffer only between the get callback and the end
+// of the `vpx_codec_decode` call that requested it, before any frame can
+// reference it. Afterwards the data is only read, see `Storage` for the
+// mutable access.
+unsafe impl Send for PooledBuffer {}
+unsafe impl Sync for PooledBuffer {}
+
+impl PooledBuffer {
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let data = unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(self.data, self.len)) };
+
+        if let Some(pool) = self.pool.upgrade() {
+            pool.free_list().push(data);
+        }
+    }
+}
+
+/// `vpx_get_frame_buffer_cb_fn_t` backed by a `FramePool`
+///
+/// `cb_priv` must come from `Arc::as_ptr` on a `FramePool` outliving
+/// the decoder.
+pub(crate) unsafe extern "C" fn get_frame_buffer(
+    cb_priv: *mut c_void,
+    min_size: usize,
+    fb: *mut vpx_codec_frame_buffer_t,
+) -> c_int {
+    if cb_priv.is_null() || fb.is_null() {
+        return -1;
+    }
+
+    // Borrow the pool without taking over the decoder reference.
+    let pool = ManuallyDrop::new(Arc::from_raw(cb_priv as *const FramePool));
+    let buf = pool.get(min_size);
+
+    (*fb).data = buf.data;
+    (*fb).size = buf.len;
+    (*fb).priv_ = Arc::into_raw(buf) as *mut c_void;
+
+    0
+}
+
+/// `vpx_release_frame_buffer_cb_fn_t` matching `get_frame_buffer`
+pub(crate) unsafe extern "C" fn release_frame_buffer(
+    _cb_priv: *mut c_void,
+    fb: *mut vpx_codec_frame_buffer_t,
+) -> c_int {
+    if fb.is_null() {
+        return -1;
+    }
+
+    let buf = (*fb).priv_ as *const PooledBuffer;
+    if !buf.is_null() {
+        drop(Arc::from_raw(buf));
+    }
+
+    (*fb).data = ptr::null_mut();
+    (*fb).size = 0;
+    (*fb).priv_ = ptr::null_mut();
+
+    0
+}
+
+/// Take a new reference to the buffer backing a decoded image
+///
+/// # Safety
+///
+/// `fb_priv` must be null or come from `get_frame_buffer` and still be
+/// held by libvpx.
+pub(crate) unsafe fn image_buffer(fb_priv: *mut c_void) -> Option<Arc<PooledBuffer>> {
+    let buf = fb_priv as *const PooledBuffer;
+    if buf.is_null() {
+        None
+    } else {
+        Arc::increment_strong_count(buf);
+        Some(Arc::from_raw(buf))
+    }
+}
+
+/// Samples of a decoded picture
+///
+/// A pooled buffer is shared with libvpx as long as the picture is used as
+/// a reference, so it is copied on the first mutable access unless the
+/// picture is the only owner left.
+#[derive(Debug)]
+pub(crate) enum Storage {
+    Shared(Arc<PooledBuffer>),
+    Owned(Box<[u8]>),
+}
+
+impl Storage {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        match *self {
+            Storage::Shared(ref buf) => buf.as_slice(),
+            Storage::Owned(ref data) => data,
+        }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        if let Storage::Shared(ref buf) = *self {
+            // Nobody else, libvpx included, can reach the buffer anymore.
+            if Arc::strong_count(buf) == 1 {
+                return unsafe { slice::from_raw_parts_mut(buf.data, buf.len) };
+            }
+            *self = Storage::Owned(buf.as_slice().into());
+        }
+
+        match *self {
+            Storage::Owned(ref mut data) => data,
+            Storage::Shared(_) => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_fb() -> vpx_codec_frame_buffer_t {
+        vpx_codec_frame_buffer_t {
+            data: ptr::null_mut(),
+            size: 0,
+            priv_: ptr::null_mut(),
+        }
+    }
+
+    #[test]
+    fn recycle() {
+        let pool = Arc::<FramePool>::default();
+        let cb_priv = Arc::as_ptr(&pool) as *mut c_void;
+        let mut fb = empty_fb();
+
+        unsafe {
+            assert_eq!(get_frame_buffer(cb_priv, 64, &mut fb), 0);
+            assert!(fb.size >= 64);
+            assert!(slice::from_raw_parts(fb.data, fb.size)
+                .iter()
+                .all(|&b| b == 0));
+
+            let data = fb.data;
+            let held = image_buffer(fb.priv_).unwrap();
+
+            assert_eq!(release_frame_buffer(cb_priv, &mut fb), 0);
+            assert!(fb.priv_.is_null());
+            assert_eq!(pool.free_count(), 0);
+
+            drop(held);
+            assert_eq!(pool.free_count(), 1);
+
+            assert_eq!(get_frame_buffer(cb_priv, 32, &mut fb), 0);
+            assert_eq!(fb.data, data);
+            assert_eq!(pool.free_count(), 0);
+
+            assert_eq!(release_frame_buffer(cb_priv, &mut fb), 0);
+            assert_eq!(get_frame_buffer(cb_priv, 128, &mut fb), 0);
+            assert!(fb.size >= 128);
+            assert_eq!(pool.free_count(), 0);
+            assert_eq!(release_frame_buffer(cb_priv, &mut fb), 0);
+        }
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let pool = Arc::<FramePool>::default();
+        let cb_priv = Arc::as_ptr(&pool) as *mut c_void;
+        let mut fb = empty_fb();
+
+        unsafe {
+            get_frame_buffer(cb_priv, 16, &mut fb);
+            *fb.data = 1;
+        }
+
+        let buf = unsafe { image_buffer(fb.priv_).unwrap() };
+        let mut storage = Storage::Shared(buf);
+
+        assert_eq!(storage.as_slice().len(), fb.size);
+        assert_eq!(storage.as_slice()[0], 1);
+
+        // Still held by the decoder, the samples must not change under it.
+        storage.as_mut_slice()[0] = 2;
+        assert_eq!(unsafe { *fb.data }, 1);
+        assert_eq!(storage.as_slice()[0], 2);
+        assert!(matches!(storage, Storage::Owned(_)));
+
+        unsafe { release_frame_buffer(cb_priv, &mut fb) };
+        assert_eq!(pool.free_count(), 1);
+
+        // The only owner left writes in place.
+        unsafe { get_frame_buffer(cb_priv, 16, &mut fb) };
+        let data = fb.data;
+        let mut storage = Storage::Shared(unsafe { image_buffer(fb.priv_).unwrap() });
+        unsafe { release_frame_buffer(cb_priv, &mut fb) };
+
+        storage.as_mut_slice()[0] = 3;
+        assert_eq!(storage.as_slice().as_ptr(), data as *const u8);
+        assert_eq!(storage.as_slice()[0], 3);
+    }
+}
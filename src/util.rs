@@ -0,0 +1,30 @@
+//! Helpers shared by the container readers
+
+use std::io::{self, Read};
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read `size` bytes, `None` if the input is already at its end
+///
+/// It fails with `UnexpectedEof` if the input ends in the middle.
+///
+/// The size comes from the file and a damaged one may claim gigabytes,
+/// so the buffer grows with the data actually read instead of being
+/// allocated upfront.
+pub(crate) fn read_untrusted<R: Read>(r: &mut R, size: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+
+    r.take(size).read_to_end(&mut data)?;
+    match data.len() as u64 {
+        0 if size != 0 => Ok(None),
+        len if len != size => Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => Ok(Some(data)),
+    }
+}
+
+/// Read `size` bytes, failing with `UnexpectedEof` if the input is shorter
+pub(crate) fn read_exact_untrusted<R: Read>(r: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    read_untrusted(r, size)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
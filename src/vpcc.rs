@@ -0,0 +1,372 @@
+//! VP Codec Configuration Record
+//!
+//! The `vpcC` record is how MP4 and WebM muxers describe a VP8/VP9
+//! stream: profile, level, bit depth, chroma subsampling and color
+//! information.
+
+#[cfg(feature = "av-data")]
+use av_data::pixel::{
+    ChromaLocation, ColorModel, Formaton, TrichromaticEncodingSystem, YUVRange, YUVSystem,
+};
+
+use crate::common::ImageFormat;
+use crate::ffi::*;
+
+/// Size of the record without the codec initialization data
+const RECORD_SIZE: usize = 8;
+
+/// Chroma subsampling as signalled in the record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// 4:2:0, chroma samples vertically aligned between two luma rows
+    Yuv420Vertical = 0,
+    /// 4:2:0, chroma samples co-located with the top-left luma sample
+    Yuv420Colocated = 1,
+    /// 4:2:2
+    Yuv422 = 2,
+    /// 4:4:4
+    Yuv444 = 3,
+}
+
+impl ChromaSubsampling {
+    fn from_u8(v: u8) -> Option<ChromaSubsampling> {
+        use self::ChromaSubsampling::*;
+        match v {
+            0 => Some(Yuv420Vertical),
+            1 => Some(Yuv420Colocated),
+            2 => Some(Yuv422),
+            3 => Some(Yuv444),
+            _ => None,
+        }
+    }
+
+    /// Derive the subsampling from the chroma shifts of a `vpx_image`
+    pub fn from_shift(x_chroma_shift: u32, y_chroma_shift: u32) -> Option<ChromaSubsampling> {
+        use self::ChromaSubsampling::*;
+        match (x_chroma_shift, y_chroma_shift) {
+            (1, 1) => Some(Yuv420Colocated),
+            (1, 0) => Some(Yuv422),
+            (0, 0) => Some(Yuv444),
+            _ => None,
+        }
+    }
+
+    /// Derive the subsampling from the chroma planes of a `Formaton`
+    #[cfg(feature = "av-data")]
+    pub fn from_formaton(fmt: &Formaton) -> Option<ChromaSubsampling> {
+        let chroma = fmt.get_chromaton(1)?;
+
+        match ChromaSubsampling::from_shift(chroma.h_ss as u32, chroma.v_ss as u32)? {
+            ChromaSubsampling::Yuv420Colocated if fmt.chroma_location == ChromaLocation::Left => {
+                Some(ChromaSubsampling::Yuv420Vertical)
+            }
+            s => Some(s),
+        }
+    }
+
+    fn is_420(self) -> bool {
+        matches!(
+            self,
+            ChromaSubsampling::Yuv420Vertical | ChromaSubsampling::Yuv420Colocated
+        )
+    }
+}
+
+/// VP Codec Configuration Record
+///
+/// Colour fields use the ISO/IEC 23001-8 code points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VPCodecConfigurationRecord {
+    pub profile: u8,
+    /// Level multiplied by ten (e.g. `31` for level 3.1)
+    pub level: u8,
+    pub bit_depth: u8,
+    pub chroma_subsampling: ChromaSubsampling,
+    pub full_range: bool,
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+}
+
+impl VPCodecConfigurationRecord {
+    /// Serialize the record
+    ///
+    /// The codec initialization data is always empty for VP8 and VP9.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            self.profile,
+            self.level,
+            (self.bit_depth << 4) | ((self.chroma_subsampling as u8) << 1) | self.full_range as u8,
+            self.colour_primaries,
+            self.transfer_characteristics,
+            self.matrix_coefficients,
+            0,
+            0,
+        ]
+    }
+
+    /// Parse a record
+    ///
+    /// Both the bare record and the full `vpcC` box payload (version 1
+    /// header included) are accepted.
+    pub fn from_bytes(data: &[u8]) -> Option<VPCodecConfigurationRecord> {
+        let data = if data.len() >= RECORD_SIZE + 4 && data[..4] == [1, 0, 0, 0] {
+            &data[4..]
+        } else {
+            data
+        };
+
+        if data.len() < RECORD_SIZE {
+            return None;
+        }
+
+        let bit_depth = data[2] >> 4;
+        if !matches!(bit_depth, 8 | 10 | 12) || data[0] > 3 {
+            return None;
+        }
+
+        let init_size = u16::from_be_bytes([data[6], data[7]]) as usize;
+        if data.len() < RECORD_SIZE + init_size {
+            return None;
+        }
+
+        Some(VPCodecConfigurationRecord {
+            profile: data[0],
+            level: data[1],
+            bit_depth,
+            chroma_subsampling: ChromaSubsampling::from_u8((data[2] >> 1) & 0x7)?,
+            full_range: data[2] & 1 != 0,
+            colour_primaries: data[3],
+            transfer_characteristics: data[4],
+            matrix_coefficients: data[5],
+        })
+    }
+
+    fn matches(&self, bit_depth: u32, subsampling: Option<ChromaSubsampling>) -> bool {
+        bit_depth == self.bit_depth as u32
+            && match subsampling {
+                // The chroma siting cannot be told from the samples alone
+                Some(s) if s.is_420() => self.chroma_subsampling.is_420(),
+                s => s == Some(self.chroma_subsampling),
+            }
+    }
+
+    /// Check that a decoded image agrees with the record
+    pub fn matches_image(&self, img: &vpx_image) -> bool {
+        self.matches(
+            img.bit_depth,
+            ChromaSubsampling::from_shift(img.x_chroma_shift, img.y_chroma_shift),
+        )
+    }
+
+    /// Check that an image layout agrees with the record
+    pub fn matches_format(&self, fmt: &ImageFormat) -> bool {
+        let (xs, ys) = fmt.chroma_shift();
+
+        self.matches(fmt.bit_depth(), ChromaSubsampling::from_shift(xs, ys))
+    }
+
+    /// libvpx color space matching the signalled matrix coefficients
+    ///
+    /// Returns `None` if VP9 cannot represent them.
+    pub fn color_space(&self) -> Option<vpx_color_space_t> {
+        color_space_from_iso(self.matrix_coefficients)
+    }
+
+    /// libvpx color range matching the record
+    pub fn color_range(&self) -> vpx_color_range_t {
+        if self.full_range {
+            vpx_color_range::VPX_CR_FULL_RANGE
+        } else {
+            vpx_color_range::VPX_CR_STUDIO_RANGE
+        }
+    }
+
+    /// Check that a pixel format agrees with the record
+    #[cfg(feature = "av-data")]
+    pub fn matches_formaton(&self, fmt: &Formaton) -> bool {
+        let depth = fmt.get_chromaton(0).map(|c| c.depth).unwrap_or(0);
+
+        self.matches(depth as u32, ChromaSubsampling::from_formaton(fmt))
+    }
+}
+
+/// Smallest VP9 level able to hold a picture of the given size
+///
+/// The frame rate is not known to the encoder, so only the luma picture
+/// size and dimension limits are taken into account.
+pub fn level_for_size(w: u32, h: u32) -> u8 {
+    // (level, max luma picture size, max luma picture dimension)
+    const LEVELS: [(u8, u64, u32); 8] = [
+        (10, 36864, 512),
+        (11, 73728, 768),
+        (20, 122880, 960),
+        (21, 245760, 1344),
+        (30, 552960, 2048),
+        (31, 983040, 2752),
+        (40, 2228224, 4160),
+        (50, 8912896, 8384),
+    ];
+
+    let size = w as u64 * h as u64;
+    let dim = w.max(h);
+
+    LEVELS
+        .iter()
+        .find(|&&(_, max_size, max_dim)| size <= max_size && dim <= max_dim)
+        .map(|&(level, _, _)| level)
+        .unwrap_or(60)
+}
+
+/// ISO/IEC 23001-8 code point for unspecified colour information
+pub(crate) const UNSPECIFIED: u8 = 2;
+
+/// Map the libvpx color space to ISO/IEC 23001-8 primaries, transfer
+/// characteristics and matrix coefficients
+pub(crate) fn color_space_to_iso(cs: vpx_color_space_t, bit_depth: u8) -> (u8, u8, u8) {
+    use self::vpx_color_space::*;
+
+    match cs {
+        // BT.470BG primaries, BT.601 transfer and matrix
+        VPX_CS_BT_601 => (5, 6, 5),
+        VPX_CS_BT_709 => (1, 1, 1),
+        VPX_CS_SMPTE_170 => (6, 6, 6),
+        VPX_CS_SMPTE_240 => (7, 7, 7),
+        // BT.2020 non-constant luminance
+        VPX_CS_BT_2020 => (9, if bit_depth > 10 { 15 } else { 14 }, 9),
+        // BT.709 primaries, IEC 61966-2-1 transfer, identity matrix
+        VPX_CS_SRGB => (1, 13, 0),
+        _ => (UNSPECIFIED, UNSPECIFIED, UNSPECIFIED),
+    }
+}
+
+/// Map ISO/IEC 23001-8 matrix coefficients to the libvpx color space
+///
+/// Returns `None` if VP9 cannot signal them.
+pub(crate) fn color_space_from_iso(matrix_coefficients: u8) -> Option<vpx_color_space_t> {
+    use self::vpx_color_space::*;
+
+    match matrix_coefficients {
+        0 => Some(VPX_CS_SRGB),
+        1 => Some(VPX_CS_BT_709),
+        5 => Some(VPX_CS_BT_601),
+        6 => Some(VPX_CS_SMPTE_170),
+        7 => Some(VPX_CS_SMPTE_240),
+        // Both the non-constant and constant luminance variants
+        9 | 10 => Some(VPX_CS_BT_2020),
+        _ => None,
+    }
+}
+
+/// Tell whether a pixel format carries full range samples
+///
+/// Returns `None` if the format does not say.
+#[cfg(feature = "av-data")]
+pub(crate) fn formaton_full_range(fmt: &Formaton) -> Option<bool> {
+    use self::TrichromaticEncodingSystem::*;
+    match fmt.model {
+        ColorModel::Trichromatic(YUV(YUVSystem::YCbCr(YUVRange::Full))) => Some(true),
+        ColorModel::Trichromatic(YUV(YUVSystem::YCbCr(YUVRange::Limited))) => Some(false),
+        ColorModel::Trichromatic(RGB) => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> VPCodecConfigurationRecord {
+        VPCodecConfigurationRecord {
+            profile: 0,
+            level: 31,
+            bit_depth: 8,
+            chroma_subsampling: ChromaSubsampling::Yuv420Colocated,
+            full_range: false,
+            colour_primaries: 1,
+            transfer_characteristics: 1,
+            matrix_coefficients: 1,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let r = record();
+        let b = r.to_bytes();
+
+        assert_eq!(b, [0, 31, 0x82, 1, 1, 1, 0, 0]);
+        assert_eq!(VPCodecConfigurationRecord::from_bytes(&b), Some(r));
+
+        let mut full_box = vec![1, 0, 0, 0];
+        full_box.extend_from_slice(&b);
+        assert_eq!(VPCodecConfigurationRecord::from_bytes(&full_box), Some(r));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(VPCodecConfigurationRecord::from_bytes(&[0, 31, 0x82]), None);
+        assert_eq!(
+            VPCodecConfigurationRecord::from_bytes(&[0, 31, 0x02, 1, 1, 1, 0, 0]),
+            None
+        );
+        assert_eq!(
+            VPCodecConfigurationRecord::from_bytes(&[0, 31, 0x82, 1, 1, 1, 0, 4]),
+            None
+        );
+    }
+
+    #[test]
+    fn format() {
+        use self::vpx_img_fmt_t::*;
+
+        let r = record();
+
+        assert!(r.matches_format(&ImageFormat::new(VPX_IMG_FMT_I420, 8).unwrap()));
+        assert!(!r.matches_format(&ImageFormat::new(VPX_IMG_FMT_I444, 8).unwrap()));
+        assert!(!r.matches_format(&ImageFormat::new(VPX_IMG_FMT_I42016, 10).unwrap()));
+    }
+
+    #[cfg(feature = "av-data")]
+    #[test]
+    fn formaton() {
+        use av_data::pixel::formats::{YUV420, YUV420_10, YUV444};
+
+        let r = record();
+
+        assert!(r.matches_formaton(YUV420));
+        assert!(!r.matches_formaton(YUV444));
+        assert!(!r.matches_formaton(YUV420_10));
+    }
+
+    #[test]
+    fn color_space() {
+        use self::vpx_color_space::*;
+
+        for &cs in &[
+            VPX_CS_BT_601,
+            VPX_CS_BT_709,
+            VPX_CS_SMPTE_170,
+            VPX_CS_SMPTE_240,
+            VPX_CS_BT_2020,
+            VPX_CS_SRGB,
+        ] {
+            let (_, _, matrix) = color_space_to_iso(cs, 8);
+            assert_eq!(color_space_from_iso(matrix), Some(cs));
+        }
+
+        assert_eq!(color_space_to_iso(VPX_CS_BT_2020, 12), (9, 15, 9));
+        assert_eq!(color_space_from_iso(UNSPECIFIED), None);
+
+        let r = record();
+        assert_eq!(r.color_space(), Some(VPX_CS_BT_709));
+        assert_eq!(r.color_range(), vpx_color_range::VPX_CR_STUDIO_RANGE);
+    }
+
+    #[test]
+    fn level() {
+        assert_eq!(level_for_size(176, 144), 10);
+        assert_eq!(level_for_size(1280, 720), 31);
+        assert_eq!(level_for_size(1920, 1080), 40);
+        assert_eq!(level_for_size(3840, 2160), 50);
+    }
+}
@@ -0,0 +1,1022 @@
+//! WebM container
+//!
+//! A minimal Matroska subset: a single VP8 or VP9 video track stored in
+//! SimpleBlocks or BlockGroups. Laced blocks are not supported, the
+//! writer stores one frame per SimpleBlock and adds Cues on finish.
+
+use crate::common::{ImageFormat, VPXCodecId};
+use crate::encoder::{VP9EncoderConfig, VPXPacket};
+use crate::util::{invalid_data, read_exact_untrusted};
+use crate::vpcc::VPCodecConfigurationRecord;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+mod id {
+    pub const EBML: u32 = 0x1A45DFA3;
+    pub const EBML_VERSION: u32 = 0x4286;
+    pub const EBML_READ_VERSION: u32 = 0x42F7;
+    pub const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+    pub const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+    pub const DOC_TYPE: u32 = 0x4282;
+    pub const DOC_TYPE_VERSION: u32 = 0x4287;
+    pub const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+    pub const SEGMENT: u32 = 0x18538067;
+    pub const SEEK_HEAD: u32 = 0x114D9B74;
+    pub const SEEK: u32 = 0x4DBB;
+    pub const SEEK_ID: u32 = 0x53AB;
+    pub const SEEK_POSITION: u32 = 0x53AC;
+    pub const INFO: u32 = 0x1549A966;
+    pub const TIMECODE_SCALE: u32 = 0x2AD7B1;
+    pub const DURATION: u32 = 0x4489;
+    pub const MUXING_APP: u32 = 0x4D80;
+    pub const WRITING_APP: u32 = 0x5741;
+    pub const TRACKS: u32 = 0x1654AE6B;
+    pub const TRACK_ENTRY: u32 = 0xAE;
+    pub const TRACK_NUMBER: u32 = 0xD7;
+    pub const TRACK_UID: u32 = 0x73C5;
+    pub const TRACK_TYPE: u32 = 0x83;
+    pub const CODEC_ID: u32 = 0x86;
+    pub const CODEC_PRIVATE: u32 = 0x63A2;
+    pub const VIDEO: u32 = 0xE0;
+    pub const PIXEL_WIDTH: u32 = 0xB0;
+    pub const PIXEL_HEIGHT: u32 = 0xBA;
+    pub const DISPLAY_WIDTH: u32 = 0x54B0;
+    pub const DISPLAY_HEIGHT: u32 = 0x54BA;
+    pub const CLUSTER: u32 = 0x1F43B675;
+    pub const TIMECODE: u32 = 0xE7;
+    pub const SIMPLE_BLOCK: u32 = 0xA3;
+    pub const BLOCK_GROUP: u32 = 0xA0;
+    pub const BLOCK: u32 = 0xA1;
+    pub const BLOCK_DURATION: u32 = 0x9B;
+    pub const REFERENCE_BLOCK: u32 = 0xFB;
+    pub const CUES: u32 = 0x1C53BB6B;
+    pub const CUE_POINT: u32 = 0xBB;
+    pub const CUE_TIME: u32 = 0xB3;
+    pub const CUE_TRACK_POSITIONS: u32 = 0xB7;
+    pub const CUE_TRACK: u32 = 0xF7;
+    pub const CUE_CLUSTER_POSITION: u32 = 0xF1;
+}
+
+/// Nanoseconds per timecode unit used by the writer
+const TIMECODE_SCALE: u64 = 1_000_000;
+const TRACK_NUMBER: u64 = 1;
+const TRACK_TYPE_VIDEO: u64 = 1;
+
+const FLAG_KEY: u8 = 0x80;
+const FLAG_INVISIBLE: u8 = 0x08;
+const FLAG_LACING: u8 = 0x06;
+
+fn put_id(buf: &mut Vec<u8>, id: u32) {
+    let len = 4 - id.leading_zeros() as usize / 8;
+
+    buf.extend_from_slice(&id.to_be_bytes()[4 - len..]);
+}
+
+fn put_vint(buf: &mut Vec<u8>, v: u64, len: usize) {
+    let v = v | 1 << (7 * len);
+
+    buf.extend_from_slice(&v.to_be_bytes()[8 - len..]);
+}
+
+fn put_size(buf: &mut Vec<u8>, size: u64) {
+    // All ones is reserved for the unknown size.
+    let len = (1..=8)
+        .find(|&len| size < (1 << (7 * len)) - 1)
+        .expect("EBML sizes are limited to 2^56 - 2");
+
+    put_vint(buf, size, len);
+}
+
+fn put_element(buf: &mut Vec<u8>, id: u32, body: &[u8]) {
+    put_id(buf, id);
+    put_size(buf, body.len() as u64);
+    buf.extend_from_slice(body);
+}
+
+fn put_uint(buf: &mut Vec<u8>, id: u32, v: u64) {
+    let len = (8 - v.leading_zeros() as usize / 8).max(1);
+
+    put_element(buf, id, &v.to_be_bytes()[8 - len..]);
+}
+
+/// Store a value that is updated later, return the offset of its data
+fn put_fixed(buf: &mut Vec<u8>, id: u32, v: [u8; 8]) -> usize {
+    put_id(buf, id);
+    put_size(buf, 8);
+    buf.extend_from_slice(&v);
+
+    buf.len() - 8
+}
+
+fn element(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(body.len() + 12);
+    put_element(&mut buf, id, body);
+
+    buf
+}
+
+/// Read an EBML variable size integer, `None` at the end of the input
+fn read_vint<R: Read>(r: &mut R, keep_marker: bool) -> io::Result<Option<(u64, usize)>> {
+    let mut first = [0];
+    loop {
+        match r.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let len = first[0].leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(invalid_data("Invalid EBML variable size integer"));
+    }
+
+    let mut rest = [0; 7];
+    r.read_exact(&mut rest[..len - 1])?;
+
+    let first = match keep_marker {
+        true => first[0],
+        false => first[0] & (0xff_u32 >> len) as u8,
+    };
+    let v = rest[..len - 1]
+        .iter()
+        .fold(u64::from(first), |v, &b| v << 8 | u64::from(b));
+
+    Ok(Some((v, len)))
+}
+
+/// Read an element id and size, the size is `None` if unknown
+fn read_element_header<R: Read>(r: &mut R) -> io::Result<Option<(u32, Option<u64>)>> {
+    let id = match read_vint(r, true)? {
+        Some((id, len)) if len <= 4 => id as u32,
+        Some(_) => return Err(invalid_data("Invalid EBML element id")),
+        None => return Ok(None),
+    };
+    let (size, len) = read_vint(r, false)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+    let size = match size == (1 << (7 * len)) - 1 {
+        true => None,
+        false => Some(size),
+    };
+
+    Ok(Some((id, size)))
+}
+
+fn known_size(size: Option<u64>) -> io::Result<u64> {
+    size.ok_or_else(|| invalid_data("Unexpected element of unknown size"))
+}
+
+fn read_body<R: Read>(r: &mut R, size: Option<u64>) -> io::Result<Vec<u8>> {
+    read_exact_untrusted(r, known_size(size)?)
+}
+
+fn skip<R: Read>(r: &mut R, size: Option<u64>) -> io::Result<()> {
+    let size = known_size(size)?;
+
+    if io::copy(&mut r.take(size), &mut io::sink())? != size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(())
+}
+
+/// Split the body of a master element in its children
+fn children(mut data: &[u8]) -> io::Result<Vec<(u32, &[u8])>> {
+    let mut out = Vec::new();
+
+    while let Some((id, size)) = read_element_header(&mut data)? {
+        let size = known_size(size)?;
+        if size > data.len() as u64 {
+            return Err(invalid_data("Truncated EBML element"));
+        }
+
+        let (body, rest) = data.split_at(size as usize);
+        out.push((id, body));
+        data = rest;
+    }
+
+    Ok(out)
+}
+
+fn parse_uint(data: &[u8]) -> io::Result<u64> {
+    if data.len() > 8 {
+        return Err(invalid_data("Invalid EBML unsigned integer"));
+    }
+
+    Ok(data.iter().fold(0, |v, &b| v << 8 | u64::from(b)))
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+/// Encode the VP9 `CodecPrivate` features
+///
+/// Profile, level (if known), bit depth and chroma subsampling, as
+/// described by the WebM codec mapping.
+pub fn codec_private(record: &VPCodecConfigurationRecord) -> Vec<u8> {
+    let mut data = vec![1, 1, record.profile];
+
+    if record.level != 0 {
+        data.extend_from_slice(&[2, 1, record.level]);
+    }
+    data.extend_from_slice(&[3, 1, record.bit_depth]);
+    data.extend_from_slice(&[4, 1, record.chroma_subsampling as u8]);
+
+    data
+}
+
+/// Video track description
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub codec: VPXCodecId,
+    pub width: u32,
+    pub height: u32,
+    /// Size the pictures are meant to be displayed at, if it differs from
+    /// the coded size
+    pub display_size: Option<(u32, u32)>,
+    /// `CodecPrivate`, empty if absent
+    pub codec_private: Vec<u8>,
+}
+
+impl Track {
+    /// Describe the stream a VP9 encoder configuration produces
+    ///
+    /// `format` is the layout of the images that will be encoded, if
+    /// known, and provides the chroma subsampling.
+    ///
+    /// The track is always VP9, as [`VP9EncoderConfig`] only drives the
+    /// VP9 encoder. Build the `Track` directly to describe a VP8 stream.
+    pub fn from_vp9_config(cfg: &VP9EncoderConfig, format: Option<ImageFormat>) -> Track {
+        Track {
+            codec: VPXCodecId::VP9,
+            width: cfg.cfg.g_w,
+            height: cfg.cfg.g_h,
+            display_size: cfg.render_size,
+            codec_private: codec_private(&cfg.codec_config_record(format)),
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let codec_id: &[u8] = match self.codec {
+            VPXCodecId::VP8 => b"V_VP8",
+            VPXCodecId::VP9 => b"V_VP9",
+        };
+
+        let mut video = Vec::new();
+        put_uint(&mut video, id::PIXEL_WIDTH, u64::from(self.width));
+        put_uint(&mut video, id::PIXEL_HEIGHT, u64::from(self.height));
+        if let Some((w, h)) = self.display_size {
+            put_uint(&mut video, id::DISPLAY_WIDTH, u64::from(w));
+            put_uint(&mut video, id::DISPLAY_HEIGHT, u64::from(h));
+        }
+
+        let mut entry = Vec::new();
+        put_uint(&mut entry, id::TRACK_NUMBER, TRACK_NUMBER);
+        put_uint(&mut entry, id::TRACK_UID, TRACK_NUMBER);
+        put_uint(&mut entry, id::TRACK_TYPE, TRACK_TYPE_VIDEO);
+        put_element(&mut entry, id::CODEC_ID, codec_id);
+        if !self.codec_private.is_empty() {
+            put_element(&mut entry, id::CODEC_PRIVATE, &self.codec_private);
+        }
+        put_element(&mut entry, id::VIDEO, &video);
+
+        element(id::TRACK_ENTRY, &entry)
+    }
+
+    /// Parse a TrackEntry, `None` if it is not a VP8 or VP9 video track
+    fn parse(data: &[u8]) -> io::Result<Option<(u64, Track)>> {
+        let mut number = 0;
+        let mut kind = 0;
+        let mut codec = None;
+        let mut track = Track {
+            codec: VPXCodecId::VP9,
+            width: 0,
+            height: 0,
+            display_size: None,
+            codec_private: Vec::new(),
+        };
+        let (mut display_w, mut display_h) = (None, None);
+
+        for (id, body) in children(data)? {
+            match id {
+                id::TRACK_NUMBER => number = parse_uint(body)?,
+                id::TRACK_TYPE => kind = parse_uint(body)?,
+                id::CODEC_ID => {
+                    codec = match body {
+                        b"V_VP8" => Some(VPXCodecId::VP8),
+                        b"V_VP9" => Some(VPXCodecId::VP9),
+                        _ => None,
+                    }
+                }
+                id::CODEC_PRIVATE => track.codec_private = body.to_vec(),
+                id::VIDEO => {
+                    for (id, body) in children(body)? {
+                        match id {
+                            id::PIXEL_WIDTH => track.width = parse_uint(body)? as u32,
+                            id::PIXEL_HEIGHT => track.height = parse_uint(body)? as u32,
+                            id::DISPLAY_WIDTH => display_w = Some(parse_uint(body)? as u32),
+                            id::DISPLAY_HEIGHT => display_h = Some(parse_uint(body)? as u32),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let (Some(w), Some(h)) = (display_w, display_h) {
+            track.display_size = Some((w, h));
+        }
+
+        match (kind, codec) {
+            (TRACK_TYPE_VIDEO, Some(codec)) => {
+                track.codec = codec;
+                Ok(Some((number, track)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Compressed frame stored in a WebM file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    /// Presentation timestamp, in timebase units
+    pub pts: i64,
+    /// Duration, in timebase units, if stored
+    pub duration: Option<u64>,
+    pub is_key: bool,
+    /// The frame is not meant to be displayed, e.g. an alternate reference
+    pub is_invisible: bool,
+}
+
+#[cfg(feature = "av-data")]
+impl From<Packet> for av_data::packet::Packet {
+    fn from(pkt: Packet) -> Self {
+        let mut p = av_data::packet::Packet::new();
+
+        p.data = pkt.data;
+        p.t.pts = Some(pkt.pts);
+        p.t.duration = pkt.duration;
+        p.is_key = pkt.is_key;
+
+        p
+    }
+}
+
+/// WebM file reader
+///
+/// It returns the packets of the first VP8 or VP9 video track, the other
+/// tracks are skipped. It is an iterator over the packets of the file.
+pub struct Reader<R> {
+    inner: R,
+    track: Track,
+    track_number: u64,
+    timecode_scale: u64,
+    cluster_time: i64,
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the headers up to the track description
+    ///
+    /// It fails with `InvalidData` if the file is not a WebM or Matroska
+    /// file storing VP8 or VP9.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let (id, size) = read_element_header(&mut inner)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if id != id::EBML {
+            return Err(invalid_data("Not a WebM file"));
+        }
+
+        for (id, body) in children(&read_body(&mut inner, size)?)? {
+            if id == id::DOC_TYPE && body != b"webm" && body != b"matroska" {
+                return Err(invalid_data("Unsupported DocType"));
+            }
+        }
+
+        let mut timecode_scale = TIMECODE_SCALE;
+
+        loop {
+            let (id, size) = read_element_header(&mut inner)?
+                .ok_or_else(|| invalid_data("No VP8 or VP9 track"))?;
+
+            match id {
+                // Its children are read in sequence.
+                id::SEGMENT => {}
+                id::INFO => {
+                    for (id, body) in children(&read_body(&mut inner, size)?)? {
+                        if id == id::TIMECODE_SCALE {
+                            timecode_scale = parse_uint(body)?;
+                        }
+                    }
+                }
+                id::TRACKS => {
+                    for (id, body) in children(&read_body(&mut inner, size)?)? {
+                        if id != id::TRACK_ENTRY {
+                            continue;
+                        }
+                        if let Some((track_number, track)) = Track::parse(body)? {
+                            if timecode_scale == 0 || timecode_scale > u64::from(u32::MAX) {
+                                return Err(invalid_data("Unsupported TimecodeScale"));
+                            }
+
+                            return Ok(Reader {
+                                inner,
+                                track,
+                                track_number,
+                                timecode_scale,
+                                cluster_time: 0,
+                            });
+                        }
+                    }
+
+                    return Err(invalid_data("No VP8 or VP9 track"));
+                }
+                id::CLUSTER => return Err(invalid_data("Missing Tracks")),
+                _ => skip(&mut inner, size)?,
+            }
+        }
+    }
+
+    /// Return the video track description
+    pub fn track(&self) -> &Track {
+        &self.track
+    }
+
+    /// Return the timebase of the packets, as numerator and denominator
+    pub fn timebase(&self) -> (u32, u32) {
+        let den = 1_000_000_000;
+        let d = gcd(self.timecode_scale, den);
+
+        ((self.timecode_scale / d) as u32, (den / d) as u32)
+    }
+
+    fn parse_block(&self, mut data: &[u8], simple: bool) -> io::Result<Option<Packet>> {
+        let (track, _) = read_vint(&mut data, false)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if data.len() < 3 {
+            return Err(invalid_data("Truncated block"));
+        }
+        if track != self.track_number {
+            return Ok(None);
+        }
+
+        let time = i16::from_be_bytes([data[0], data[1]]);
+        let flags = data[2];
+        if flags & FLAG_LACING != 0 {
+            return Err(invalid_data("Laced blocks are not supported"));
+        }
+
+        let pts = self
+            .cluster_time
+            .checked_add(i64::from(time))
+            .ok_or_else(|| invalid_data("Cluster timecode out of range"))?;
+
+        Ok(Some(Packet {
+            data: data[3..].to_vec(),
+            pts,
+            duration: None,
+            is_key: simple && flags & FLAG_KEY != 0,
+            is_invisible: flags & FLAG_INVISIBLE != 0,
+        }))
+    }
+
+    /// Read the next packet of the track
+    ///
+    /// It returns `None` at the end of the file.
+    pub fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let (id, size) = match read_element_header(&mut self.inner)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+
+            match id {
+                // Live streams store them with an unknown size.
+                id::SEGMENT | id::CLUSTER => {}
+                id::TIMECODE => {
+                    let time = parse_uint(&read_body(&mut self.inner, size)?)?;
+                    self.cluster_time = i64::try_from(time)
+                        .map_err(|_| invalid_data("Cluster timecode out of range"))?;
+                }
+                id::SIMPLE_BLOCK => {
+                    let body = read_body(&mut self.inner, size)?;
+                    if let Some(pkt) = self.parse_block(&body, true)? {
+                        return Ok(Some(pkt));
+                    }
+                }
+                id::BLOCK_GROUP => {
+                    let body = read_body(&mut self.inner, size)?;
+                    let mut block = None;
+                    let mut duration = None;
+                    let mut reference = false;
+
+                    for (id, body) in children(&body)? {
+                        match id {
+                            id::BLOCK => block = Some(body),
+                            id::BLOCK_DURATION => duration = Some(parse_uint(body)?),
+                            id::REFERENCE_BLOCK => reference = true,
+                            _ => {}
+                        }
+                    }
+
+                    let block = block.ok_or_else(|| invalid_data("BlockGroup without Block"))?;
+                    if let Some(mut pkt) = self.parse_block(block, false)? {
+                        pkt.is_key = !reference;
+                        pkt.duration = duration;
+                        return Ok(Some(pkt));
+                    }
+                }
+                _ => skip(&mut self.inner, size)?,
+            }
+        }
+    }
+
+    /// Return the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_packet().transpose()
+    }
+}
+
+/// WebM file writer
+///
+/// A new Cluster starts at every keyframe, the Cues pointing to them,
+/// the duration and the Segment size are written by [`finish`].
+///
+/// [`finish`]: #method.finish
+pub struct Writer<W: Write + Seek> {
+    inner: W,
+    timebase: (u32, u32),
+    // Absolute position of the Segment data, the positions in the
+    // SeekHead and the Cues are relative to it.
+    segment: u64,
+    segment_size_pos: u64,
+    cues_seek_pos: u64,
+    duration_pos: u64,
+    cluster: Vec<u8>,
+    cluster_time: Option<i64>,
+    cluster_key: bool,
+    cues: Vec<(u64, u64)>,
+    end_time: i64,
+}
+
+fn seek_head(info: u64, tracks: u64, cues: u64) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for &(id, pos) in &[(id::INFO, info), (id::TRACKS, tracks), (id::CUES, cues)] {
+        let mut seek = Vec::new();
+        put_element(&mut seek, id::SEEK_ID, &id.to_be_bytes());
+        put_fixed(&mut seek, id::SEEK_POSITION, pos.to_be_bytes());
+        put_element(&mut body, id::SEEK, &seek);
+    }
+
+    element(id::SEEK_HEAD, &body)
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Write the headers at the current position
+    ///
+    /// The pts of the packets are in `timebase` units, as numerator and
+    /// denominator.
+    pub fn new(mut inner: W, track: &Track, timebase: (u32, u32)) -> io::Result<Self> {
+        if timebase.0 == 0 || timebase.1 == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid timebase",
+            ));
+        }
+
+        let start = inner.stream_position()?;
+
+        let mut ebml = Vec::new();
+        put_uint(&mut ebml, id::EBML_VERSION, 1);
+        put_uint(&mut ebml, id::EBML_READ_VERSION, 1);
+        put_uint(&mut ebml, id::EBML_MAX_ID_LENGTH, 4);
+        put_uint(&mut ebml, id::EBML_MAX_SIZE_LENGTH, 8);
+        put_element(&mut ebml, id::DOC_TYPE, b"webm");
+        put_uint(&mut ebml, id::DOC_TYPE_VERSION, 4);
+        put_uint(&mut ebml, id::DOC_TYPE_READ_VERSION, 2);
+
+        let mut buf = element(id::EBML, &ebml);
+        put_id(&mut buf, id::SEGMENT);
+        let segment_size_pos = buf.len();
+        put_vint(&mut buf, (1 << 56) - 1, 8);
+        let segment = buf.len();
+
+        let mut info = Vec::new();
+        put_uint(&mut info, id::TIMECODE_SCALE, TIMECODE_SCALE);
+        put_element(&mut info, id::MUXING_APP, b"libvpx-rs");
+        put_element(&mut info, id::WRITING_APP, b"libvpx-rs");
+        let duration_pos = put_fixed(&mut info, id::DURATION, 0f64.to_be_bytes());
+        let duration_pos = duration_pos + element(id::INFO, &info).len() - info.len();
+        let info = element(id::INFO, &info);
+        let tracks = element(id::TRACKS, &track.serialize());
+
+        // The positions have a fixed size, so has the SeekHead.
+        let info_pos = seek_head(0, 0, 0).len() as u64;
+        let tracks_pos = info_pos + info.len() as u64;
+        let seek_head = seek_head(info_pos, tracks_pos, 0);
+
+        buf.extend_from_slice(&seek_head);
+        buf.extend_from_slice(&info);
+        buf.extend_from_slice(&tracks);
+        inner.write_all(&buf)?;
+
+        let segment = start + segment as u64;
+
+        Ok(Writer {
+            inner,
+            timebase,
+            segment,
+            segment_size_pos: start + segment_size_pos as u64,
+            cues_seek_pos: segment + seek_head.len() as u64 - 8,
+            duration_pos: segment + info_pos + duration_pos as u64,
+            cluster: Vec::new(),
+            cluster_time: None,
+            cluster_key: false,
+            cues: Vec::new(),
+            end_time: 0,
+        })
+    }
+
+    fn timecode(&self, pts: i64) -> i64 {
+        let (num, den) = self.timebase;
+        let ns = i128::from(pts) * i128::from(num) * 1_000_000_000 / i128::from(den);
+
+        (ns / i128::from(TIMECODE_SCALE)) as i64
+    }
+
+    fn flush_cluster(&mut self) -> io::Result<()> {
+        if let Some(time) = self.cluster_time.take() {
+            let pos = self.inner.stream_position()? - self.segment;
+            if self.cluster_key {
+                self.cues.push((time as u64, pos));
+            }
+
+            let mut header = Vec::new();
+            put_id(&mut header, id::CLUSTER);
+            put_size(&mut header, self.cluster.len() as u64);
+            self.inner.write_all(&header)?;
+            self.inner.write_all(&self.cluster)?;
+            self.cluster.clear();
+        }
+
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        data: &[u8],
+        pts: i64,
+        duration: u64,
+        is_key: bool,
+        is_invisible: bool,
+    ) -> io::Result<()> {
+        let time = self.timecode(pts);
+        if time < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Negative timestamps are not supported",
+            ));
+        }
+
+        let relative = self.cluster_time.map(|start| time - start);
+        let fits =
+            matches!(relative, Some(t) if i64::from(i16::MIN) <= t && t <= i64::from(i16::MAX));
+        if !fits || is_key {
+            self.flush_cluster()?;
+            self.cluster_time = Some(time);
+            self.cluster_key = is_key;
+            put_uint(&mut self.cluster, id::TIMECODE, time as u64);
+        }
+        let relative = (time - self.cluster_time.unwrap_or(time)) as i16;
+
+        let mut flags = 0;
+        if is_key {
+            flags |= FLAG_KEY;
+        }
+        if is_invisible {
+            flags |= FLAG_INVISIBLE;
+        }
+
+        let mut header = Vec::with_capacity(4);
+        put_vint(&mut header, TRACK_NUMBER, 1);
+        header.extend_from_slice(&relative.to_be_bytes());
+        header.push(flags);
+
+        put_id(&mut self.cluster, id::SIMPLE_BLOCK);
+        put_size(&mut self.cluster, (header.len() + data.len()) as u64);
+        self.cluster.extend_from_slice(&header);
+        self.cluster.extend_from_slice(data);
+
+        let end = self.timecode(pts.saturating_add(duration as i64));
+        self.end_time = self.end_time.max(end).max(time);
+
+        Ok(())
+    }
+
+    /// Write the frame carried by an encoder packet
+    ///
+    /// Statistics and the other kinds of packets are skipped.
+    pub fn write_packet(&mut self, pkt: &VPXPacket) -> io::Result<()> {
+        match pkt {
+            VPXPacket::Packet(pkt) => self.write_block(
+                &pkt.data,
+                pkt.pts,
+                pkt.duration,
+                pkt.is_key,
+                pkt.is_invisible,
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write a packet, e.g. one read from another file
+    pub fn write(&mut self, pkt: &Packet) -> io::Result<()> {
+        self.write_block(
+            &pkt.data,
+            pkt.pts,
+            pkt.duration.unwrap_or(0),
+            pkt.is_key,
+            pkt.is_invisible,
+        )
+    }
+
+    /// Write the Cues, update the headers and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_cluster()?;
+
+        let cues_pos = self.inner.stream_position()? - self.segment;
+        if !self.cues.is_empty() {
+            let mut cues = Vec::new();
+            for &(time, pos) in &self.cues {
+                let mut track = Vec::new();
+                put_uint(&mut track, id::CUE_TRACK, TRACK_NUMBER);
+                put_uint(&mut track, id::CUE_CLUSTER_POSITION, pos);
+
+                let mut point = Vec::new();
+                put_uint(&mut point, id::CUE_TIME, time);
+                put_element(&mut point, id::CUE_TRACK_POSITIONS, &track);
+
+                put_element(&mut cues, id::CUE_POINT, &point);
+            }
+            self.inner.write_all(&element(id::CUES, &cues))?;
+
+            self.inner.seek(SeekFrom::Start(self.cues_seek_pos))?;
+            self.inner.write_all(&cues_pos.to_be_bytes())?;
+        }
+
+        self.inner.seek(SeekFrom::End(0))?;
+        let end = self.inner.stream_position()?;
+
+        let mut size = Vec::new();
+        put_vint(&mut size, end - self.segment, 8);
+        self.inner.seek(SeekFrom::Start(self.segment_size_pos))?;
+        self.inner.write_all(&size)?;
+
+        self.inner.seek(SeekFrom::Start(self.duration_pos))?;
+        self.inner
+            .write_all(&(self.end_time as f64).to_be_bytes())?;
+
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::{VP9Decoder, VP9DecoderConfig};
+    use crate::encoder::tests as enc;
+    use std::io::Cursor;
+
+    fn track() -> Track {
+        Track {
+            codec: VPXCodecId::VP9,
+            width: 64,
+            height: 48,
+            display_size: Some((128, 48)),
+            codec_private: vec![1, 1, 0, 3, 1, 8, 4, 1, 1],
+        }
+    }
+
+    fn packet(pts: i64, is_key: bool) -> Packet {
+        Packet {
+            data: vec![pts as u8; 10 + pts as usize],
+            pts,
+            duration: Some(1),
+            is_key,
+            is_invisible: pts == 2,
+        }
+    }
+
+    #[test]
+    fn vint() {
+        let mut buf = Vec::new();
+        put_size(&mut buf, 126);
+        put_size(&mut buf, 127);
+        put_size(&mut buf, 0x3ffe);
+        put_size(&mut buf, 0x3fff);
+        assert_eq!(buf, [0xfe, 0x40, 0x7f, 0x7f, 0xfe, 0x20, 0x3f, 0xff]);
+
+        let mut data = &buf[..];
+        let sizes: Vec<_> = (0..4)
+            .map(|_| read_vint(&mut data, false).unwrap().unwrap().0)
+            .collect();
+        assert_eq!(sizes, [126, 127, 0x3ffe, 0x3fff]);
+        assert!(read_vint(&mut data, false).unwrap().is_none());
+
+        let mut buf = Vec::new();
+        put_id(&mut buf, id::SEGMENT);
+        put_id(&mut buf, id::TIMECODE);
+        assert_eq!(buf, [0x18, 0x53, 0x80, 0x67, 0xe7]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut w = Writer::new(Cursor::new(Vec::new()), &track(), (1, 30)).unwrap();
+        for pts in 0..6 {
+            w.write(&packet(pts, pts % 3 == 0)).unwrap();
+        }
+        let buf = w.finish().unwrap().into_inner();
+
+        let mut r = Reader::new(Cursor::new(&buf)).unwrap();
+        assert_eq!(r.track(), &track());
+        assert_eq!(r.timebase(), (1, 1000));
+
+        let packets: Vec<_> = r.by_ref().map(Result::unwrap).collect();
+        assert_eq!(packets.len(), 6);
+        for (i, pkt) in packets.iter().enumerate() {
+            let expected = packet(i as i64, i % 3 == 0);
+            assert_eq!(pkt.data, expected.data);
+            assert_eq!(pkt.pts, i as i64 * 1000 / 30);
+            assert_eq!(pkt.duration, None);
+            assert_eq!(pkt.is_key, expected.is_key);
+            assert_eq!(pkt.is_invisible, expected.is_invisible);
+        }
+
+        // The SeekHead points to the Cues, one per keyframe
+        let segment = buf
+            .windows(4)
+            .position(|w| w == [0x18, 0x53, 0x80, 0x67])
+            .unwrap()
+            + 12;
+        let cues = buf
+            .windows(4)
+            .rposition(|w| w == [0x1c, 0x53, 0xbb, 0x6b])
+            .unwrap();
+        let seek = cues - segment;
+        assert!(buf.windows(8).any(|w| w == (seek as u64).to_be_bytes()));
+
+        let mut data = &buf[cues..];
+        let (id, size) = read_element_header(&mut data).unwrap().unwrap();
+        assert_eq!((id, size), (id::CUES, Some(data.len() as u64)));
+        let points = children(data).unwrap();
+        assert_eq!(points.len(), 2);
+        assert!(points.iter().all(|&(id, _)| id == id::CUE_POINT));
+    }
+
+    #[test]
+    fn block_group() {
+        let mut tracks = Vec::new();
+        let mut audio = Vec::new();
+        put_uint(&mut audio, id::TRACK_NUMBER, 1);
+        put_uint(&mut audio, id::TRACK_TYPE, 2);
+        put_element(&mut audio, id::CODEC_ID, b"A_OPUS");
+        put_element(&mut tracks, id::TRACK_ENTRY, &audio);
+        let mut video = Track::serialize(&Track {
+            codec: VPXCodecId::VP8,
+            display_size: None,
+            codec_private: Vec::new(),
+            ..track()
+        });
+        // Track number 2
+        let pos = video.iter().position(|&b| b == 0xd7).unwrap();
+        video[pos + 2] = 2;
+        tracks.extend_from_slice(&video);
+
+        let mut buf = Vec::new();
+        put_element(&mut buf, id::EBML, &element(id::DOC_TYPE, b"matroska"));
+        put_id(&mut buf, id::SEGMENT);
+        put_vint(&mut buf, (1 << 56) - 1, 8);
+        put_element(&mut buf, 0xEC, &[0; 10]);
+        put_element(&mut buf, id::TRACKS, &tracks);
+        put_id(&mut buf, id::CLUSTER);
+        put_vint(&mut buf, (1 << 56) - 1, 8);
+        put_uint(&mut buf, id::TIMECODE, 100);
+        // Audio block
+        put_element(&mut buf, id::SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 1, 2]);
+        let mut group = Vec::new();
+        put_element(&mut group, id::BLOCK, &[0x82, 0xff, 0xfb, 0, 3, 4]);
+        put_uint(&mut group, id::BLOCK_DURATION, 33);
+        put_uint(&mut group, id::REFERENCE_BLOCK, 1);
+        put_element(&mut buf, id::BLOCK_GROUP, &group);
+        put_element(&mut buf, id::SIMPLE_BLOCK, &[0x82, 0, 40, 0x80, 5]);
+
+        let mut r = Reader::new(Cursor::new(buf)).unwrap();
+        assert_eq!(r.track().codec, VPXCodecId::VP8);
+        assert_eq!(r.track().display_size, None);
+
+        let pkt = r.read_packet().unwrap().unwrap();
+        assert_eq!(pkt.data, [3, 4]);
+        assert_eq!(pkt.pts, 95);
+        assert_eq!(pkt.duration, Some(33));
+        assert!(!pkt.is_key);
+
+        let pkt = r.read_packet().unwrap().unwrap();
+        assert_eq!(pkt.data, [5]);
+        assert_eq!(pkt.pts, 140);
+        assert!(pkt.is_key);
+
+        assert!(r.read_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        let err = Reader::new(Cursor::new(b"DKIF")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = element(id::EBML, &element(id::DOC_TYPE, b"webm"));
+        put_element(&mut buf, id::SEGMENT, &[]);
+        let err = Reader::new(Cursor::new(buf)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let header = Writer::new(Cursor::new(Vec::new()), &track(), (1, 30))
+            .unwrap()
+            .finish()
+            .unwrap()
+            .into_inner();
+
+        // Timecode not fitting the pts
+        let mut buf = header.clone();
+        put_id(&mut buf, id::CLUSTER);
+        put_vint(&mut buf, (1 << 56) - 1, 8);
+        put_uint(&mut buf, id::TIMECODE, u64::MAX);
+        put_element(&mut buf, id::SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 1]);
+        let mut r = Reader::new(Cursor::new(buf)).unwrap();
+        let err = r.read_packet().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Block time overflowing the pts
+        let mut buf = header;
+        put_id(&mut buf, id::CLUSTER);
+        put_vint(&mut buf, (1 << 56) - 1, 8);
+        put_uint(&mut buf, id::TIMECODE, i64::MAX as u64);
+        put_element(&mut buf, id::SIMPLE_BLOCK, &[0x81, 0, 1, 0x80, 1]);
+        let mut r = Reader::new(Cursor::new(buf)).unwrap();
+        let err = r.read_packet().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        let mut c = VP9EncoderConfig::new().unwrap();
+        c.cfg.g_w = 64;
+        c.cfg.g_h = 48;
+        let track = Track::from_vp9_config(&c, None);
+        assert_eq!(&track.codec_private[..3], [1, 1, 0]);
+
+        let mut w = Writer::new(Cursor::new(Vec::new()), &track, (1, 1000)).unwrap();
+        for i in 0..6 {
+            match i {
+                5 => e.flush().unwrap(),
+                _ => enc::encode_pattern(&mut e, i),
+            }
+            for pkt in e.packets() {
+                w.write_packet(&pkt).unwrap();
+            }
+        }
+        let buf = w.finish().unwrap().into_inner();
+
+        let r = Reader::new(Cursor::new(buf)).unwrap();
+        let cfg = VP9DecoderConfig {
+            codec: r.track().codec,
+            ..VP9DecoderConfig::new()
+        };
+        let mut d: VP9Decoder<i64> = cfg.get_decoder().unwrap();
+        let frames = d
+            .decode_all(r.map(Result::unwrap).map(|pkt| (pkt.data, Some(pkt.pts))))
+            .unwrap();
+
+        assert_eq!(frames.len(), 5);
+        for (pts, (frame, private)) in frames.iter().enumerate() {
+            assert_eq!(private.as_deref(), Some(&(pts as i64)));
+            assert_eq!((frame.width(), frame.height()), (64, 48));
+        }
+    }
+}
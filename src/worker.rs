@@ -0,0 +1,442 @@
+//! Asynchronous encoding and decoding
+//!
+//! The codec runs on a dedicated thread so the blocking libvpx calls do not
+//! stall the executor. Pictures and packets travel over bounded channels:
+//! sending waits once `capacity` items are queued and the codec thread waits
+//! once `capacity` results are not consumed.
+//!
+//! Closing the input flushes the codec, the output stream ends after the
+//! last delayed result.
+//!
+//! Sending and receiving must make progress together, [`AsyncEncoder::split`]
+//! and [`AsyncDecoder::split`] give two halves that can live in separate
+//! tasks.
+
+use crate::decoder::{DecodedFrame, VP9Decoder, VP9DecoderConfig};
+use crate::encoder::{VP9Encoder, VP9EncoderConfig, VPXPacket};
+use crate::ffi::*;
+use crate::image::Image;
+
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, Stream, StreamExt};
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+use self::vpx_codec_err_t::*;
+
+/// Run `process` on every input received on a new thread
+///
+/// `None` is passed once the input is closed, the thread stops after it or
+/// as soon as the output is dropped.
+fn spawn<C, I, O, F>(
+    name: &str,
+    mut codec: C,
+    capacity: usize,
+    mut process: F,
+) -> Result<(mpsc::Sender<I>, mpsc::Receiver<O>), vpx_codec_err_t>
+where
+    C: Send + 'static,
+    I: Send + 'static,
+    O: Send + 'static,
+    F: FnMut(&mut C, Option<I>) -> Vec<O> + Send + 'static,
+{
+    let (in_tx, mut in_rx) = mpsc::channel(capacity);
+    let (mut out_tx, out_rx) = mpsc::channel(capacity);
+
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || {
+            block_on(async move {
+                loop {
+                    let input = in_rx.next().await;
+                    let last = input.is_none();
+
+                    for out in process(&mut codec, input) {
+                        if out_tx.send(out).await.is_err() {
+                            return;
+                        }
+                    }
+
+                    if last {
+                        return;
+                    }
+                }
+            })
+        })
+        .map_err(|_| VPX_CODEC_MEM_ERROR)?;
+
+    Ok((in_tx, out_rx))
+}
+
+struct EncodeRequest {
+    img: Image,
+    pts: i64,
+    duration: u64,
+    flags: vpx_enc_frame_flags_t,
+}
+
+fn encode(enc: &mut VP9Encoder, req: Option<EncodeRequest>) -> Vec<EncoderOutput> {
+    let ret = match req {
+        Some(req) => enc.encode_vpx_image(&req.img, req.pts, req.duration, req.flags),
+        None => enc.flush(),
+    };
+
+    match ret {
+        Ok(()) => enc.packets().map(Ok).collect(),
+        Err(err) => vec![Err(err)],
+    }
+}
+
+type EncoderOutput = Result<VPXPacket, vpx_codec_err_t>;
+
+/// Sending half of an [`AsyncEncoder`]
+pub struct FrameSender {
+    tx: mpsc::Sender<EncodeRequest>,
+}
+
+impl FrameSender {
+    /// Queue a picture for encoding
+    ///
+    /// It waits while the queue is full and fails with `VPX_CODEC_ERROR`
+    /// once the encoder is closed.
+    ///
+    /// Encoding errors are reported by the packet stream.
+    pub async fn send_frame(
+        &mut self,
+        img: Image,
+        pts: i64,
+        duration: u64,
+        flags: vpx_enc_frame_flags_t,
+    ) -> Result<(), vpx_codec_err_t> {
+        let req = EncodeRequest {
+            img,
+            pts,
+            duration,
+            flags,
+        };
+
+        self.tx.send(req).await.map_err(|_| VPX_CODEC_ERROR)
+    }
+
+    /// Notify the encoder that no more pictures will be sent
+    ///
+    /// The encoder is flushed once the queued pictures are encoded.
+    pub fn close(&mut self) {
+        self.tx.close_channel();
+    }
+}
+
+/// Receiving half of an [`AsyncEncoder`]
+pub struct PacketStream {
+    rx: mpsc::Receiver<EncoderOutput>,
+}
+
+impl Stream for PacketStream {
+    type Item = EncoderOutput;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// VP9 Encoder running on its own thread
+///
+/// It is a [`Stream`] of the packets produced.
+pub struct AsyncEncoder {
+    sender: FrameSender,
+    packets: PacketStream,
+}
+
+impl AsyncEncoder {
+    /// Create a new encoder holding at most `capacity` pictures and
+    /// `capacity` packets in flight
+    pub fn new(cfg: &mut VP9EncoderConfig, capacity: usize) -> Result<Self, vpx_codec_err_t> {
+        let enc = VP9Encoder::new(cfg)?;
+        let (tx, rx) = spawn("vpx-encoder", enc, capacity, encode)?;
+
+        Ok(AsyncEncoder {
+            sender: FrameSender { tx },
+            packets: PacketStream { rx },
+        })
+    }
+
+    /// Queue a picture for encoding, see [`FrameSender::send_frame`]
+    pub async fn send_frame(
+        &mut self,
+        img: Image,
+        pts: i64,
+        duration: u64,
+        flags: vpx_enc_frame_flags_t,
+    ) -> Result<(), vpx_codec_err_t> {
+        self.sender.send_frame(img, pts, duration, flags).await
+    }
+
+    /// Notify the encoder that no more pictures will be sent
+    pub fn close(&mut self) {
+        self.sender.close()
+    }
+
+    /// Separate the sending and the receiving halves
+    pub fn split(self) -> (FrameSender, PacketStream) {
+        (self.sender, self.packets)
+    }
+}
+
+impl Stream for AsyncEncoder {
+    type Item = EncoderOutput;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.packets).poll_next(cx)
+    }
+}
+
+type DecoderOutput<T> = Result<(DecodedFrame, Option<Box<T>>), vpx_codec_err_t>;
+
+fn decode<T>(dec: &mut VP9Decoder<T>, req: Option<(Vec<u8>, Option<T>)>) -> Vec<DecoderOutput<T>> {
+    let ret = match req {
+        Some((data, private)) => dec.decode(&data, private),
+        None => dec.flush(),
+    };
+
+    match ret {
+        Ok(()) => dec.frames().map(Ok).collect(),
+        Err(err) => vec![Err(err)],
+    }
+}
+
+/// Sending half of an [`AsyncDecoder`]
+pub struct PacketSender<T> {
+    tx: mpsc::Sender<(Vec<u8>, Option<T>)>,
+}
+
+impl<T> PacketSender<T> {
+    /// Queue a packet for decoding
+    ///
+    /// `private` is returned with the picture the packet produces, as for
+    /// [`VP9Decoder::decode`].
+    ///
+    /// It waits while the queue is full and fails with `VPX_CODEC_ERROR`
+    /// once the decoder is closed.
+    ///
+    /// Decoding errors are reported by the frame stream.
+    pub async fn send_packet<O>(&mut self, data: Vec<u8>, private: O) -> Result<(), vpx_codec_err_t>
+    where
+        O: Into<Option<T>>,
+    {
+        self.tx
+            .send((data, private.into()))
+            .await
+            .map_err(|_| VPX_CODEC_ERROR)
+    }
+
+    /// Notify the decoder that no more packets will be sent
+    ///
+    /// The decoder is flushed once the queued packets are decoded.
+    pub fn close(&mut self) {
+        self.tx.close_channel();
+    }
+}
+
+/// Receiving half of an [`AsyncDecoder`]
+pub struct FrameStream<T> {
+    rx: mpsc::Receiver<DecoderOutput<T>>,
+}
+
+impl<T> Stream for FrameStream<T> {
+    type Item = DecoderOutput<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// VP9 Decoder running on its own thread
+///
+/// It is a [`Stream`] of the pictures decoded and their private data.
+pub struct AsyncDecoder<T> {
+    sender: PacketSender<T>,
+    frames: FrameStream<T>,
+}
+
+impl<T: Send + 'static> AsyncDecoder<T> {
+    /// Create a new decoder holding at most `capacity` packets and
+    /// `capacity` pictures in flight
+    pub fn new(cfg: &VP9DecoderConfig, capacity: usize) -> Result<Self, vpx_codec_err_t> {
+        let dec = VP9Decoder::with_config(cfg)?;
+        let (tx, rx) = spawn("vpx-decoder", dec, capacity, decode)?;
+
+        Ok(AsyncDecoder {
+            sender: PacketSender { tx },
+            frames: FrameStream { rx },
+        })
+    }
+}
+
+impl<T> AsyncDecoder<T> {
+    /// Queue a packet for decoding, see [`PacketSender::send_packet`]
+    pub async fn send_packet<O>(&mut self, data: Vec<u8>, private: O) -> Result<(), vpx_codec_err_t>
+    where
+        O: Into<Option<T>>,
+    {
+        self.sender.send_packet(data, private).await
+    }
+
+    /// Notify the decoder that no more packets will be sent
+    pub fn close(&mut self) {
+        self.sender.close()
+    }
+
+    /// Separate the sending and the receiving halves
+    pub fn split(self) -> (PacketSender<T>, FrameStream<T>) {
+        (self.sender, self.frames)
+    }
+}
+
+impl<T> Stream for AsyncDecoder<T> {
+    type Item = DecoderOutput<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.frames).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ImageFormat;
+    use futures::future::join;
+
+    fn image(w: u32, h: u32, i: u32) -> Image {
+        let format = ImageFormat::new(vpx_img_fmt_t::VPX_IMG_FMT_I420, 8).unwrap();
+        let mut img = Image::new(format, w, h, 1).unwrap();
+
+        for (y, row) in img.rows_mut(0).enumerate() {
+            for (x, v) in row.iter_mut().enumerate() {
+                *v = (x + y + i as usize * 8) as u8;
+            }
+        }
+        for idx in 1..3 {
+            img.plane_mut(idx).fill(128);
+        }
+
+        img
+    }
+
+    fn config(w: u32, h: u32) -> VP9EncoderConfig {
+        let mut c = VP9EncoderConfig::new().unwrap();
+        c.cfg.g_w = w;
+        c.cfg.g_h = h;
+        c.cfg.g_timebase.num = 1;
+        c.cfg.g_timebase.den = 1000;
+        c.cfg.g_pass = vpx_enc_pass::VPX_RC_ONE_PASS;
+
+        c
+    }
+
+    fn encode_async(n: u32) -> Vec<VPXPacket> {
+        let enc = AsyncEncoder::new(&mut config(64, 48), 2).unwrap();
+        let (mut sender, packets) = enc.split();
+
+        let send = async move {
+            for i in 0..n {
+                sender
+                    .send_frame(image(64, 48, i), i as i64, 1, 0)
+                    .await
+                    .unwrap();
+            }
+            sender.close();
+        };
+        let recv = packets.map(Result::unwrap).collect::<Vec<_>>();
+
+        block_on(join(send, recv)).1
+    }
+
+    #[test]
+    fn encode() {
+        let packets: Vec<_> = encode_async(10)
+            .into_iter()
+            .filter_map(|p| match p {
+                VPXPacket::Packet(pkt) => Some(pkt),
+                _ => None,
+            })
+            .collect();
+
+        // The stream ends only after the flush returned the delayed packets.
+        assert_eq!(packets.len(), 10);
+        assert!(packets[0].is_key);
+        for (pts, pkt) in packets.iter().enumerate() {
+            assert_eq!(pkt.pts, pts as i64);
+        }
+    }
+
+    #[test]
+    fn encode_error() {
+        let mut enc = AsyncEncoder::new(&mut config(64, 48), 1).unwrap();
+
+        block_on(async {
+            enc.send_frame(image(32, 32, 0), 0, 1, 0).await.unwrap();
+            assert!(matches!(
+                enc.next().await,
+                Some(Err(VPX_CODEC_INVALID_PARAM))
+            ));
+
+            enc.close();
+            assert_eq!(
+                enc.send_frame(image(64, 48, 0), 0, 1, 0).await,
+                Err(VPX_CODEC_ERROR)
+            );
+            while let Some(pkt) = enc.next().await {
+                pkt.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn decode() {
+        let packets: Vec<_> = encode_async(10)
+            .into_iter()
+            .filter_map(|p| match p {
+                VPXPacket::Packet(pkt) => Some(pkt),
+                _ => None,
+            })
+            .collect();
+
+        let dec = AsyncDecoder::new(&VP9DecoderConfig::new(), 1).unwrap();
+        let (mut sender, frames) = dec.split();
+
+        let send = async move {
+            for pkt in packets {
+                sender.send_packet(pkt.data, pkt.pts).await.unwrap();
+            }
+        };
+        let recv = frames.map(Result::unwrap).collect::<Vec<_>>();
+
+        // Dropping the sender closes the input as well.
+        let frames = block_on(join(send, recv)).1;
+
+        assert_eq!(frames.len(), 10);
+        for (pts, (frame, private)) in frames.iter().enumerate() {
+            assert_eq!(private.as_deref(), Some(&(pts as i64)));
+            assert_eq!((frame.width(), frame.height()), (64, 48));
+        }
+    }
+
+    #[test]
+    fn drop_pending() {
+        let packets = encode_async(3);
+
+        // Nobody reads the frames, dropping the decoder stops the thread.
+        let mut dec = AsyncDecoder::<()>::new(&VP9DecoderConfig::new(), 0).unwrap();
+        block_on(async {
+            for p in packets {
+                if let VPXPacket::Packet(pkt) = p {
+                    dec.send_packet(pkt.data, None).await.unwrap();
+                }
+            }
+        });
+        drop(dec);
+    }
+}
@@ -0,0 +1,781 @@
+//! YUV4MPEG2 files
+//!
+//! The uncompressed format of the standard test sequences: a text header
+//! followed by pictures, each introduced by a `FRAME` line and stored as
+//! contiguous planes.
+
+use crate::common::ImageFormat;
+use crate::decoder::DecodedImage;
+use crate::util::{invalid_data, read_untrusted};
+
+use av_data::frame::{
+    Frame, FrameBuffer, FrameBufferConv, FrameError, FrameType, MediaKind, VideoInfo,
+};
+use av_data::pixel::Formaton;
+use av_data::rational::Rational64;
+use av_data::timeinfo::TimeInfo;
+use vpx_sys::*;
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+const SIGNATURE: &str = "YUV4MPEG2";
+const FRAME: &str = "FRAME";
+const MAX_LINE: usize = 1024;
+/// Largest picture side VP9 can code
+const MAX_SIZE: u32 = 65536;
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Convert deep samples between the little endian of the files and the
+/// native endian of the planes, the same swap works both ways
+fn swap_samples(data: &mut [u8]) {
+    if cfg!(target_endian = "big") {
+        for sample in data.chunks_exact_mut(2) {
+            sample.swap(0, 1);
+        }
+    }
+}
+
+/// Read a line without its terminator, `None` at the end of the input
+fn read_line<R: Read>(r: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+
+    loop {
+        match r.read(&mut byte) {
+            Ok(0) if line.is_empty() => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) if line.len() == MAX_LINE => return Err(invalid_data("Y4M line too long")),
+            Ok(_) => line.push(byte[0]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid_data("Invalid Y4M line"))
+}
+
+fn parse_ratio(val: &str) -> Option<(u32, u32)> {
+    let mut it = val.splitn(2, ':');
+    let num = it.next()?.parse().ok()?;
+    let den = it.next()?.parse().ok()?;
+
+    Some((num, den))
+}
+
+/// Map a Y4M colorspace to the image layout
+fn parse_colorspace(val: &str) -> Option<ImageFormat> {
+    use self::vpx_img_fmt_t::*;
+
+    let (sampling, depth) = match val {
+        "420jpeg" | "420paldv" | "420mpeg2" => ("420", 8),
+        _ => match val.find('p') {
+            Some(pos) => (&val[..pos], val[pos + 1..].parse().ok()?),
+            None => (val, 8),
+        },
+    };
+
+    let fmt = match (sampling, depth > 8) {
+        ("420", false) => VPX_IMG_FMT_I420,
+        ("422", false) => VPX_IMG_FMT_I422,
+        ("444", false) => VPX_IMG_FMT_I444,
+        ("440", false) => VPX_IMG_FMT_I440,
+        ("420", true) => VPX_IMG_FMT_I42016,
+        ("422", true) => VPX_IMG_FMT_I42216,
+        ("444", true) => VPX_IMG_FMT_I44416,
+        ("440", true) => VPX_IMG_FMT_I44016,
+        _ => return None,
+    };
+
+    ImageFormat::new(fmt, depth)
+}
+
+fn colorspace(format: ImageFormat) -> String {
+    let sampling = match format.chroma_shift() {
+        (1, 1) if format.bit_depth() == 8 => return "420jpeg".into(),
+        (1, 1) => "420",
+        (1, 0) => "422",
+        (0, 1) => "440",
+        _ => "444",
+    };
+
+    match format.bit_depth() {
+        8 => sampling.into(),
+        depth => format!("{}p{}", sampling, depth),
+    }
+}
+
+/// Interlacing of the pictures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interlacing {
+    Progressive,
+    TopFieldFirst,
+    BottomFieldFirst,
+    /// Signalled per picture
+    Mixed,
+}
+
+/// Y4M stream header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+    /// Frames per second, as numerator and denominator
+    pub framerate: (u32, u32),
+    /// Pixel aspect ratio, `(0, 0)` if unknown
+    pub aspect: (u32, u32),
+    pub interlacing: Interlacing,
+    /// The samples use the full range, as signalled by `XCOLORRANGE=FULL`
+    pub full_range: bool,
+}
+
+impl Header {
+    /// Describe progressive `width`x`height` pictures at 30 frames per
+    /// second
+    pub fn new(width: u32, height: u32, format: ImageFormat) -> Header {
+        Header {
+            width,
+            height,
+            format,
+            framerate: (30, 1),
+            aspect: (0, 0),
+            interlacing: Interlacing::Progressive,
+            full_range: false,
+        }
+    }
+
+    /// Describe the pictures returned by a decoder
+    pub fn from_image(img: &DecodedImage<'_>, framerate: (u32, u32)) -> Header {
+        Header {
+            framerate,
+            full_range: img.color_range() == vpx_color_range_t::VPX_CR_FULL_RANGE,
+            ..Header::new(img.width(), img.height(), img.format())
+        }
+    }
+
+    /// Timebase of the frame pts, one tick per picture
+    pub fn timebase(&self) -> (u32, u32) {
+        (self.framerate.1, self.framerate.0)
+    }
+
+    /// Describe the pixel format as a `Formaton`
+    pub fn formaton(&self) -> Formaton {
+        let range = match self.full_range {
+            true => vpx_color_range_t::VPX_CR_FULL_RANGE,
+            false => vpx_color_range_t::VPX_CR_STUDIO_RANGE,
+        };
+
+        self.format
+            .color_formaton(vpx_color_space_t::VPX_CS_UNKNOWN, range)
+    }
+
+    /// Describe the pictures as a `VideoInfo`
+    pub fn video_info(&self) -> VideoInfo {
+        VideoInfo::new(
+            self.width as usize,
+            self.height as usize,
+            false,
+            FrameType::OTHER,
+            Arc::new(self.formaton()),
+        )
+    }
+
+    /// Size in bytes of a picture, `None` if it does not fit in `usize`
+    pub fn frame_size(&self) -> Option<usize> {
+        (0..3).try_fold(0usize, |size, idx| {
+            let (w, h) = self.format.plane_size(idx, self.width, self.height);
+            w.checked_mul(h)?
+                .checked_mul(self.format.bytes_per_sample())?
+                .checked_add(size)
+        })
+    }
+
+    fn parse(line: &str) -> io::Result<Header> {
+        let mut tokens = line.split(' ').filter(|t| !t.is_empty());
+        if tokens.next() != Some(SIGNATURE) {
+            return Err(invalid_data("Not a Y4M file"));
+        }
+
+        let (mut width, mut height) = (None, None);
+        let mut header = Header::new(0, 0, parse_colorspace("420jpeg").unwrap());
+
+        for token in tokens {
+            let mut chars = token.chars();
+            let tag = chars.next();
+            let val = chars.as_str();
+
+            match tag {
+                Some('W') => width = val.parse().ok(),
+                Some('H') => height = val.parse().ok(),
+                Some('F') => {
+                    header.framerate = parse_ratio(val)
+                        .filter(|&(num, den)| num != 0 && den != 0)
+                        .ok_or_else(|| invalid_data("Invalid Y4M frame rate"))?
+                }
+                Some('A') => {
+                    header.aspect =
+                        parse_ratio(val).ok_or_else(|| invalid_data("Invalid Y4M aspect ratio"))?
+                }
+                Some('I') => {
+                    header.interlacing = match val {
+                        "p" | "?" => Interlacing::Progressive,
+                        "t" => Interlacing::TopFieldFirst,
+                        "b" => Interlacing::BottomFieldFirst,
+                        "m" => Interlacing::Mixed,
+                        _ => return Err(invalid_data("Invalid Y4M interlacing")),
+                    }
+                }
+                Some('C') => {
+                    header.format = parse_colorspace(val)
+                        .ok_or_else(|| invalid_data("Unsupported Y4M colorspace"))?
+                }
+                Some('X') if val == "COLORRANGE=FULL" => header.full_range = true,
+                Some('X') if val == "COLORRANGE=LIMITED" => header.full_range = false,
+                // Other extensions and unknown tags are ignored.
+                _ => {}
+            }
+        }
+
+        header.width = width.unwrap_or(0);
+        header.height = height.unwrap_or(0);
+
+        match header.is_valid() {
+            true => Ok(header),
+            false => Err(invalid_data("Invalid Y4M picture size")),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        let valid = |side| 0 < side && side <= MAX_SIZE;
+
+        valid(self.width) && valid(self.height) && self.frame_size().is_some()
+    }
+
+    fn serialize(&self) -> String {
+        let interlacing = match self.interlacing {
+            Interlacing::Progressive => 'p',
+            Interlacing::TopFieldFirst => 't',
+            Interlacing::BottomFieldFirst => 'b',
+            Interlacing::Mixed => 'm',
+        };
+
+        let mut line = format!(
+            "{} W{} H{} F{}:{} I{}",
+            SIGNATURE, self.width, self.height, self.framerate.0, self.framerate.1, interlacing,
+        );
+        if self.aspect != (0, 0) {
+            line.push_str(&format!(" A{}:{}", self.aspect.0, self.aspect.1));
+        }
+
+        // The same layout as vpxdec, so that the digests of the outputs match.
+        let colorspace = colorspace(self.format);
+        line.push_str(&format!(" C{}", colorspace));
+        if colorspace != "420jpeg" {
+            line.push_str(&format!(" XYSCSS={}", colorspace.to_uppercase()));
+        }
+        if self.full_range {
+            line.push_str(" XCOLORRANGE=FULL");
+        }
+        line.push('\n');
+
+        line
+    }
+}
+
+/// Contiguous planes of a picture read from a Y4M file
+struct Planes {
+    planes: [Vec<u8>; 3],
+    strides: [usize; 3],
+}
+
+impl FrameBuffer for Planes {
+    fn linesize(&self, idx: usize) -> Result<usize, FrameError> {
+        self.strides
+            .get(idx)
+            .copied()
+            .ok_or(FrameError::InvalidIndex)
+    }
+
+    fn count(&self) -> usize {
+        3
+    }
+
+    fn as_slice_inner(&self, idx: usize) -> Result<&[u8], FrameError> {
+        self.planes
+            .get(idx)
+            .map(|p| &p[..])
+            .ok_or(FrameError::InvalidIndex)
+    }
+
+    fn as_mut_slice_inner(&mut self, idx: usize) -> Result<&mut [u8], FrameError> {
+        self.planes
+            .get_mut(idx)
+            .map(|p| &mut p[..])
+            .ok_or(FrameError::InvalidIndex)
+    }
+}
+
+/// Y4M file reader
+///
+/// The frames carry their index as pts, in [`Header::timebase`] units.
+/// Samples deeper than 8 bits are stored as native endian `u16`, as the
+/// encoder expects them. It is an iterator over the frames of the
+/// file.
+///
+/// [`Header::timebase`]: struct.Header.html#method.timebase
+pub struct Reader<R> {
+    inner: R,
+    header: Header,
+    info: VideoInfo,
+    pts: i64,
+    raw: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the stream header
+    ///
+    /// It fails with `InvalidData` if the header is not valid or the
+    /// colorspace is not supported.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let line = read_line(&mut inner)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let header = Header::parse(&line)?;
+
+        Ok(Reader {
+            inner,
+            info: header.video_info(),
+            header,
+            pts: 0,
+            raw: false,
+        })
+    }
+
+    /// Read headerless pictures, as described by `header`
+    ///
+    /// The input is the bare planes of each picture, as stored by
+    /// [`Writer::raw`]. It fails with `InvalidInput` if the picture size
+    /// is zero or too large.
+    ///
+    /// [`Writer::raw`]: struct.Writer.html#method.raw
+    pub fn raw(inner: R, header: &Header) -> io::Result<Self> {
+        if !header.is_valid() {
+            return Err(invalid_input("Invalid Y4M picture size"));
+        }
+
+        Ok(Reader {
+            inner,
+            info: header.video_info(),
+            header: *header,
+            pts: 0,
+            raw: true,
+        })
+    }
+
+    /// Return the stream header
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Read the next picture
+    ///
+    /// It returns `None` at the end of the file and fails with
+    /// `UnexpectedEof` if the last picture is truncated.
+    pub fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        if !self.raw {
+            match read_line(&mut self.inner)? {
+                Some(ref line) if line.split(' ').next() == Some(FRAME) => {}
+                Some(_) => return Err(invalid_data("Missing Y4M FRAME marker")),
+                None => return Ok(None),
+            }
+        }
+
+        let Header {
+            width,
+            height,
+            format,
+            ..
+        } = self.header;
+        let mut planes = Planes {
+            planes: Default::default(),
+            strides: [0; 3],
+        };
+
+        for idx in 0..3 {
+            let (w, h) = format.plane_size(idx, width, height);
+            let stride = w * format.bytes_per_sample();
+            let size = (stride * h) as u64;
+
+            let mut data = match read_untrusted(&mut self.inner, size)? {
+                Some(data) => data,
+                // Raw planes have no FRAME marker, the stream ends here
+                None if self.raw && idx == 0 => return Ok(None),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+            if format.is_high_bitdepth() {
+                swap_samples(&mut data);
+            }
+            planes.planes[idx] = data;
+            planes.strides[idx] = stride;
+        }
+
+        let (num, den) = self.header.timebase();
+        let t = TimeInfo {
+            pts: Some(self.pts),
+            dts: Some(self.pts),
+            duration: Some(1),
+            timebase: Some(Rational64::new(i64::from(num), i64::from(den))),
+            user_private: None,
+        };
+        self.pts += 1;
+
+        Ok(Some(Frame {
+            kind: MediaKind::Video(self.info.clone()),
+            buf: Box::new(planes),
+            t,
+        }))
+    }
+
+    /// Return the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Y4M file writer
+pub struct Writer<W> {
+    inner: W,
+    header: Header,
+    raw: bool,
+}
+
+impl<W: Write> Writer<W> {
+    /// Write the stream header
+    ///
+    /// It fails with `InvalidInput` if the picture size is zero or too
+    /// large.
+    pub fn new(inner: W, header: &Header) -> io::Result<Self> {
+        let mut w = Writer::raw(inner, header)?;
+
+        w.raw = false;
+        w.inner.write_all(header.serialize().as_bytes())?;
+
+        Ok(w)
+    }
+
+    /// Write the bare planes of the pictures, without any header
+    ///
+    /// It fails with `InvalidInput` if the picture size is zero or too
+    /// large.
+    pub fn raw(inner: W, header: &Header) -> io::Result<Self> {
+        if !header.is_valid() {
+            return Err(invalid_input("Invalid Y4M picture size"));
+        }
+
+        Ok(Writer {
+            inner,
+            header: *header,
+            raw: true,
+        })
+    }
+
+    /// Return the stream header
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn write_planes(&mut self, planes: &[&[u8]; 3], strides: &[usize; 3]) -> io::Result<()> {
+        let Header {
+            width,
+            height,
+            format,
+            ..
+        } = self.header;
+
+        format
+            .check_planes(width, height, planes, strides)
+            .map_err(|_| invalid_input("Planes too small for the Y4M picture size"))?;
+
+        if !self.raw {
+            self.inner.write_all(FRAME.as_bytes())?;
+            self.inner.write_all(b"\n")?;
+        }
+
+        for idx in 0..3 {
+            let (w, h) = format.plane_size(idx, width, height);
+            let row = w * format.bytes_per_sample();
+
+            for line in planes[idx].chunks(strides[idx]).take(h) {
+                if format.is_high_bitdepth() && cfg!(target_endian = "big") {
+                    let mut line = line[..row].to_vec();
+                    swap_samples(&mut line);
+                    self.inner.write_all(&line)?;
+                } else {
+                    self.inner.write_all(&line[..row])?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a picture
+    ///
+    /// It fails with `InvalidInput` if its size or pixel format does not
+    /// match the header.
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let matches = match frame.kind {
+            MediaKind::Video(ref v) => {
+                (v.width, v.height) == (self.header.width as usize, self.header.height as usize)
+                    && ImageFormat::from_formaton(&v.format) == Some(self.header.format)
+            }
+            _ => false,
+        };
+        if !matches {
+            return Err(invalid_input("Frame not matching the Y4M header"));
+        }
+
+        let mut planes: [&[u8]; 3] = [&[]; 3];
+        let mut strides = [0; 3];
+        for idx in 0..3 {
+            let err = |_| invalid_input("Frame without 3 planes");
+            planes[idx] = frame.buf.as_slice(idx).map_err(err)?;
+            strides[idx] = frame.buf.linesize(idx).map_err(err)?;
+        }
+
+        self.write_planes(&planes, &strides)
+    }
+
+    /// Write a picture returned by a decoder
+    ///
+    /// It fails with `InvalidInput` if its size or pixel format does not
+    /// match the header.
+    pub fn write_image(&mut self, img: &DecodedImage<'_>) -> io::Result<()> {
+        if (img.width(), img.height()) != (self.header.width, self.header.height)
+            || img.format() != self.header.format
+        {
+            return Err(invalid_input("Image not matching the Y4M header"));
+        }
+
+        let planes = [img.plane(0), img.plane(1), img.plane(2)];
+        let strides = [img.stride(0), img.stride(1), img.stride(2)];
+
+        self.write_planes(&planes, &strides)
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::VP9Decoder;
+    use crate::encoder::tests as enc;
+    use std::io::Cursor;
+
+    fn sequence(header: &str, frames: u8) -> Vec<u8> {
+        let mut buf = header.as_bytes().to_vec();
+        let h = Header::parse(header.trim_end()).unwrap();
+
+        for i in 0..frames {
+            buf.extend_from_slice(b"FRAME\n");
+            buf.extend((0..h.frame_size().unwrap()).map(|j| (j as u8).wrapping_mul(i + 1)));
+        }
+
+        buf
+    }
+
+    #[test]
+    fn header() {
+        let h = Header::parse("YUV4MPEG2 W64 H48 F25:1 Ip A1:1 C420paldv XYSCSS=420PALDV").unwrap();
+        assert_eq!((h.width, h.height), (64, 48));
+        assert_eq!(h.framerate, (25, 1));
+        assert_eq!(h.timebase(), (1, 25));
+        assert_eq!(h.aspect, (1, 1));
+        assert_eq!(h.format.fmt(), vpx_img_fmt_t::VPX_IMG_FMT_I420);
+        assert_eq!(h.frame_size(), Some(64 * 48 * 3 / 2));
+
+        let h = Header::parse("YUV4MPEG2 W6 H5 F30000:1001 It C422p10 XCOLORRANGE=FULL").unwrap();
+        assert_eq!(h.interlacing, Interlacing::TopFieldFirst);
+        assert_eq!(
+            h.format,
+            ImageFormat::new(vpx_img_fmt_t::VPX_IMG_FMT_I42216, 10).unwrap()
+        );
+        assert!(h.full_range);
+        assert_eq!(h.frame_size(), Some((6 * 5 + 2 * 3 * 5) * 2));
+
+        let info = h.video_info();
+        assert_eq!((info.width, info.height), (6, 5));
+        assert_eq!(ImageFormat::from_formaton(&info.format), Some(h.format));
+
+        for bad in &[
+            "YUV4MPEG W64 H48",
+            "YUV4MPEG2 W64",
+            "YUV4MPEG2 W64 H48 F30:0",
+            "YUV4MPEG2 W64 H48 Cmono",
+            "YUV4MPEG2 W64 H48 C420p9",
+            "YUV4MPEG2 W99999999 H99999999",
+            "YUV4MPEG2 W4294967295 H1",
+        ] {
+            let err = Header::parse(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", bad);
+        }
+
+        for &line in &[
+            "YUV4MPEG2 W64 H48 F30:1 Ip C420jpeg",
+            "YUV4MPEG2 W6 H5 F30000:1001 Ib A16:15 C444p12 XYSCSS=444P12 XCOLORRANGE=FULL",
+        ] {
+            assert_eq!(Header::parse(line).unwrap().serialize().trim_end(), line);
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for header in &[
+            "YUV4MPEG2 W7 H5 F30:1 Ip C420jpeg\n",
+            "YUV4MPEG2 W7 H5 F30:1 Ip C440p10 XYSCSS=440P10\n",
+        ] {
+            let buf = sequence(header, 3);
+            let mut r = Reader::new(Cursor::new(&buf)).unwrap();
+            let mut w = Writer::new(Vec::new(), r.header()).unwrap();
+
+            for (pts, frame) in r.by_ref().enumerate() {
+                let frame = frame.unwrap();
+                assert_eq!(frame.t.pts, Some(pts as i64));
+                if pts == 0 && header.contains("p10") {
+                    // The second sample, bytes 2 and 3 in little endian
+                    let plane = frame.buf.as_slice(0).unwrap();
+                    assert_eq!(u16::from_ne_bytes([plane[2], plane[3]]), 0x0302);
+                }
+                w.write_frame(&frame).unwrap();
+            }
+            assert!(r.read_frame().unwrap().is_none());
+
+            assert_eq!(w.into_inner().unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn raw() {
+        let buf = sequence("YUV4MPEG2 W7 H5 F30:1 Ip C422 XYSCSS=422\n", 2);
+        let header = *Reader::new(Cursor::new(&buf)).unwrap().header();
+        let mut w = Writer::raw(Vec::new(), &header).unwrap();
+
+        for frame in Reader::new(Cursor::new(&buf)).unwrap() {
+            w.write_frame(&frame.unwrap()).unwrap();
+        }
+        let planes = w.into_inner().unwrap();
+        assert_eq!(planes.len(), 2 * header.frame_size().unwrap());
+
+        let r = Reader::raw(Cursor::new(&planes), &header).unwrap();
+        let mut w = Writer::new(Vec::new(), &header).unwrap();
+        for frame in r {
+            w.write_frame(&frame.unwrap()).unwrap();
+        }
+        assert_eq!(w.into_inner().unwrap(), buf);
+
+        let mut r = Reader::raw(Cursor::new(&planes[..planes.len() - 1]), &header).unwrap();
+        assert!(r.read_frame().unwrap().is_some());
+        assert_eq!(
+            r.read_frame().err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let header = Header { width: 0, ..header };
+        assert!(Reader::raw(Cursor::new(&planes), &header).is_err());
+        assert!(Writer::raw(Vec::new(), &header).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let buf = sequence("YUV4MPEG2 W8 H8 C444\n", 1);
+
+        let err = Reader::new(Cursor::new(&buf[..10])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // The allocation follows the data, not the header
+        let mut r = Reader::new(Cursor::new(b"YUV4MPEG2 W65536 H65536\nFRAME\n\0")).unwrap();
+        let err = r.read_frame().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut r = Reader::new(Cursor::new(&buf[..buf.len() - 1])).unwrap();
+        assert_eq!(
+            r.read_frame().err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let mut bad = buf.clone();
+        bad[buf.len() - 192 - 6] = b'X';
+        let mut r = Reader::new(Cursor::new(bad)).unwrap();
+        assert_eq!(
+            r.read_frame().err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // A frame of another size
+        let frame = Reader::new(Cursor::new(&buf))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let header = Header {
+            width: 4,
+            ..*Reader::new(Cursor::new(&buf)).unwrap().header()
+        };
+        let mut w = Writer::new(Vec::new(), &header).unwrap();
+        let err = w.write_frame(&frame).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let buf = sequence("YUV4MPEG2 W64 H48 F1000:1 C420jpeg\n", 5);
+        let r = Reader::new(Cursor::new(&buf)).unwrap();
+        let mut e = enc::setup_with_timebase(64, 48, 1, 1000);
+        let mut d: VP9Decoder<()> = VP9Decoder::new().unwrap();
+        let mut w = None;
+
+        for frame in r.map(Result::unwrap).map(Some).chain(Some(None)) {
+            match frame {
+                Some(frame) => e.encode(&frame).unwrap(),
+                None => e.flush().unwrap(),
+            }
+
+            for pkt in e.packets() {
+                if let crate::encoder::VPXPacket::Packet(pkt) = pkt {
+                    d.decode(&pkt.data, None).unwrap();
+                }
+            }
+
+            while let Some((img, _)) = d.get_frame_ref() {
+                let w = w.get_or_insert_with(|| {
+                    Writer::new(Vec::new(), &Header::from_image(&img, (1000, 1))).unwrap()
+                });
+                w.write_image(&img).unwrap();
+            }
+        }
+
+        let out = w.unwrap().into_inner().unwrap();
+        let r = Reader::new(Cursor::new(out)).unwrap();
+        assert_eq!(r.header().timebase(), (1, 1000));
+        assert_eq!(r.count(), 5);
+    }
+}
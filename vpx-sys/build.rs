@@ -17,7 +17,11 @@ fn format_write(builder: bindgen::Builder) -> String {
 
 fn main() {
     let libs = system_deps::Config::new().probe().unwrap();
-    let headers = libs.get_by_name("vpx").unwrap().include_paths.clone();
+    let vpx = libs.get_by_name("vpx").unwrap();
+    let headers = vpx.include_paths.clone();
+
+    // Exposed to the dependent crates as DEP_VPX_VERSION
+    println!("cargo:version={}", vpx.version);
 
     let mut builder =
         bindgen::builder()